@@ -0,0 +1,289 @@
+use rltk::RandomNumberGenerator;
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::saveload::{ConvertSaveload, Marker};
+use specs::error::NoError;
+use specs::System;
+use specs::{Read, ReadExpect, WriteExpect, ReadStorage, WriteStorage, Entity, Entities};
+use specs::Join;
+
+use super::{Transform, MoveMap};
+use super::path::{self, is_adjacent};
+use super::time::{Actor, ActionType, ActionFailure, SimClock};
+use super::combat::{Health, Faction};
+use super::vision::Viewshed;
+
+// at or below this much health in percent a monster runs for it
+const FLEE_PERCENT: i32 = 25;
+// close enough to home to stop walking back
+const HOME_RADIUS: u32 = 2;
+// one in this many idle checks a monster takes a step around its home
+const WANDER_CHANCE: i32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonsterState {
+    Idle,// hanging around home, looking for trouble
+    Chase(Entity),
+    Flee(Entity),// away from this one
+    Return,// walking home and ignoring everything on the way
+}
+
+/// Makes an actor hostile on its own. Monsters chase what they see, but never further than
+/// `leash` tiles from home.
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Monster {
+    pub home: (u32, u32),
+    pub leash: u32,
+    pub state: MonsterState,
+}
+
+impl Monster {
+    pub fn new(home: (u32, u32), leash: u32) -> Self {
+        Self { home, leash, state: MonsterState::Idle }
+    }
+}
+
+// Saved form of a Monster, entities are replaced by their save markers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MonsterData<M> {
+    home: (u32, u32),
+    leash: u32,
+    state: MonsterStateData<M>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum MonsterStateData<M> {
+    Idle,
+    Chase(M),
+    Flee(M),
+    Return,
+}
+
+impl<M: Marker> ConvertSaveload<M> for Monster {
+    type Data = MonsterData<M>;
+    type Error = NoError;
+
+    fn convert_into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+    where
+        F: FnMut(Entity) -> Option<M>,
+    {
+        // whoever it was after is gone, so it heads home
+        let state = match self.state {
+            MonsterState::Idle => Some(MonsterStateData::Idle),
+            MonsterState::Chase(target) => ids(target).map(MonsterStateData::Chase),
+            MonsterState::Flee(threat) => ids(threat).map(MonsterStateData::Flee),
+            MonsterState::Return => Some(MonsterStateData::Return),
+        };
+        Ok(MonsterData { home: self.home, leash: self.leash, state: state.unwrap_or(MonsterStateData::Return) })
+    }
+
+    fn convert_from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+    where
+        F: FnMut(M) -> Option<Entity>,
+    {
+        let state = match data.state {
+            MonsterStateData::Idle => Some(MonsterState::Idle),
+            MonsterStateData::Chase(target) => ids(target).map(MonsterState::Chase),
+            MonsterStateData::Flee(threat) => ids(threat).map(MonsterState::Flee),
+            MonsterStateData::Return => Some(MonsterState::Return),
+        };
+        Ok(Monster { home: data.home, leash: data.leash, state: state.unwrap_or(MonsterState::Return) })
+    }
+}
+
+fn tiles_apart(a: (u32, u32), b: (u32, u32)) -> u32 {
+    a.0.abs_diff(b.0).max(a.1.abs_diff(b.1))
+}
+
+// single step as a Move, so the route gets worked out again after every tile
+fn step_towards(mmap: &MoveMap, from: (u32, u32), to: (u32, u32)) -> Option<ActionType> {
+    let next = *path::a_star(mmap, from, to)?.first()?;
+    Some(ActionType::Move(next.0 as i32 - from.0 as i32, next.1 as i32 - from.1 as i32))
+}
+
+/// Picks what every monster does next: notice, chase, hit, run away or go home.
+pub struct MonsterAI;
+
+impl<'a> System<'a> for MonsterAI {
+    type SystemData = ( Entities<'a>,
+                        Read<'a, SimClock>,
+                        WriteExpect<'a, RandomNumberGenerator>,
+                        ReadExpect<'a, MoveMap>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, Viewshed>,
+                        ReadStorage<'a, Faction>,
+                        ReadStorage<'a, Health>,
+                        WriteStorage<'a, Monster>,
+                        WriteStorage<'a, Actor>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, clock, mut rand, mmap, transforms, viewsheds, factions, healths, mut monsters, mut actors) = data;
+        let now = clock.now();
+
+        for (entity, monster, actor, trans, viewshed) in (&entities, &mut monsters, &mut actors, &transforms, &viewsheds).join() {
+            let pos = (trans.r, trans.c);
+            // home got walled off, this is home now
+            if let (Some(ActionFailure::Unreachable(..)), MonsterState::Return) = (actor.take_failure(), monster.state) {
+                monster.home = pos;
+            }
+            let faction = factions.get(entity);
+            let sees = |at: (u32, u32)| viewshed.visible.binary_search(&at).is_ok();
+            let position = |other: Entity| transforms.get(other).map(|trans| (trans.r, trans.c));
+            // the closest living thing of another side in sight
+            let nearest_enemy = (&entities, &transforms, &factions, &healths).join()
+                .filter(|(_, _, other, health)| Some(*other) != faction && !health.is_dead())
+                .map(|(other, trans, _, _)| (other, (trans.r, trans.c)))
+                .filter(|(_, at)| sees(*at))
+                .min_by_key(|(_, at)| path::distance(pos, *at));
+            let hurt = healths.get(entity).is_some_and(|health| health.hp * 100 <= health.max_hp * FLEE_PERCENT);
+
+            monster.state = match monster.state {
+                MonsterState::Idle | MonsterState::Chase(_) if hurt => match nearest_enemy {
+                    Some((threat, _)) => MonsterState::Flee(threat),
+                    None if monster.state == MonsterState::Idle => MonsterState::Idle,
+                    None => MonsterState::Return,
+                },
+                MonsterState::Idle => match nearest_enemy {
+                    Some((target, _)) => MonsterState::Chase(target),
+                    None => MonsterState::Idle,
+                },
+                // gave up once the target got away, or it got too far from home
+                MonsterState::Chase(target) => match position(target) {
+                    Some(at) if sees(at) && tiles_apart(pos, monster.home) <= monster.leash => MonsterState::Chase(target),
+                    _ => MonsterState::Return,
+                },
+                MonsterState::Flee(threat) => match position(threat) {
+                    Some(at) if sees(at) => MonsterState::Flee(threat),
+                    _ => MonsterState::Return,
+                },
+                MonsterState::Return if tiles_apart(pos, monster.home) <= HOME_RADIUS => MonsterState::Idle,
+                MonsterState::Return => MonsterState::Return,
+            };
+
+            if actor.is_busy() {
+                continue;
+            }
+            match monster.state {
+                MonsterState::Idle => if rand.range(0, WANDER_CHANCE) == 0 {
+                    let (dr, dc) = [(1, 0), (-1, 0), (0, 1), (0, -1)][rand.range(0, 4) as usize];
+                    let next = (pos.0 as i32 + dr, pos.1 as i32 + dc);
+                    if next.0 >= 0 && next.1 >= 0 && tiles_apart((next.0 as u32, next.1 as u32), monster.home) <= HOME_RADIUS {
+                        actor.new_action(ActionType::Move(dr, dc), now);
+                    }
+                },
+                MonsterState::Chase(target) => if let Some(at) = position(target) {
+                    if is_adjacent(pos, at) {
+                        actor.new_action(ActionType::Attack(target), now);
+                    } else if let Some(step) = step_towards(&mmap, pos, at) {
+                        actor.new_action(step, now);
+                    }
+                },
+                MonsterState::Flee(threat) => if let Some(at) = position(threat) {
+                    // the free neighbour furthest from the threat, cornered ones fight back
+                    let away = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)].iter()
+                        .map(|&(dr, dc)| (dr, dc, (pos.0 as i32 + dr, pos.1 as i32 + dc)))
+                        .filter(|&(_, _, (r, c))| mmap.is_free_for(entity, r, c))
+                        .max_by_key(|&(_, _, (r, c))| path::distance((r as u32, c as u32), at))
+                        .filter(|&(_, _, (r, c))| path::distance((r as u32, c as u32), at) > path::distance(pos, at));
+                    match away {
+                        Some((dr, dc, _)) => {
+                            actor.new_action(ActionType::Move(dr, dc), now);
+                        },
+                        None if is_adjacent(pos, at) => {
+                            actor.new_action(ActionType::Attack(threat), now);
+                        },
+                        None => {},
+                    }
+                },
+                MonsterState::Return => {
+                    actor.new_action(ActionType::MoveTo(monster.home.0, monster.home.1), now);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{WorldExt, Join};
+
+    use super::*;
+    use crate::{Worker, WorkerTask};
+    use crate::sim::Simulation;
+
+    // a long hall, the worker at one end and a monster at the other
+    const HALL: &str = "[meta]\nspawn = 1,1\n[legend]\n. = floor\nw = wall\ng = monster\n[map]\nwwwwwwwwwwwwwwwwwwwwwwwwwwwwww\nw.........................g..w\nwwwwwwwwwwwwwwwwwwwwwwwwwwwwww\n";
+
+    fn setup() -> (Simulation, Entity, Entity) {
+        let sim = Simulation::from_text(0, HALL);
+        let worker = (&sim.ecs.entities(), &sim.ecs.read_storage::<Worker>()).join().next().unwrap().0;
+        let monster = (&sim.ecs.entities(), &sim.ecs.read_storage::<Monster>()).join().next().unwrap().0;
+        (sim, worker, monster)
+    }
+
+    fn state(sim: &Simulation, monster: Entity) -> MonsterState {
+        sim.ecs.read_storage::<Monster>().get(monster).unwrap().state
+    }
+
+    fn position(sim: &Simulation, entity: Entity) -> (u32, u32) {
+        let trans = sim.ecs.read_storage::<Transform>().get(entity).cloned().unwrap();
+        (trans.r, trans.c)
+    }
+
+    fn move_worker(sim: &mut Simulation, worker: Entity, to: (u32, u32)) {
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::MoveTo(to.0, to.1));
+    }
+
+    #[test]
+    fn worker_in_sight_gets_chased() {
+        let (mut sim, worker, monster) = setup();
+        sim.step_n(60);
+        assert_eq!(state(&sim, monster), MonsterState::Idle);
+
+        move_worker(&mut sim, worker, (20, 1));
+        for _ in 0..600 {
+            if state(&sim, monster) != MonsterState::Idle {
+                break;
+            }
+            sim.step();
+        }
+        assert_eq!(state(&sim, monster), MonsterState::Chase(worker));
+        let sight = sim.ecs.read_storage::<Viewshed>().get(monster).unwrap().range as u32;
+        assert!(tiles_apart(position(&sim, worker), position(&sim, monster)) <= sight);
+    }
+
+    #[test]
+    fn chase_ends_at_the_leash() {
+        let (mut sim, worker, monster) = setup();
+        let home = sim.ecs.read_storage::<Monster>().get(monster).unwrap().home;
+        sim.ecs.write_storage::<Monster>().get_mut(monster).unwrap().leash = 3;
+        // in sight, but further out than the leash lets it go
+        move_worker(&mut sim, worker, (19, 1));
+        let mut furthest = 0;
+        for _ in 0..600 {
+            sim.step();
+            furthest = furthest.max(tiles_apart(position(&sim, monster), home));
+        }
+        assert!(furthest <= 4, "chased {} tiles from home", furthest);
+        let health = sim.ecs.read_storage::<Health>().get(worker).cloned().unwrap();
+        assert_eq!(health.hp, health.max_hp);
+    }
+
+    #[test]
+    fn chase_ends_once_out_of_sight() {
+        let (mut sim, worker, monster) = setup();
+        let home = sim.ecs.read_storage::<Monster>().get(monster).unwrap().home;
+        // wander into sight, then run for it
+        move_worker(&mut sim, worker, (20, 1));
+        while state(&sim, monster) == MonsterState::Idle {
+            sim.step();
+        }
+        move_worker(&mut sim, worker, (1, 1));
+        sim.step_n(600);
+        assert_eq!(state(&sim, monster), MonsterState::Idle);
+        assert!(tiles_apart(position(&sim, monster), home) <= HOME_RADIUS);
+        let health = sim.ecs.read_storage::<Health>().get(worker).cloned().unwrap();
+        assert_eq!(health.hp, health.max_hp);
+    }
+}
@@ -0,0 +1,81 @@
+use specs::{WorldExt, Join};
+
+use rogue::{Transform, Worker, WorkerTask};
+use rogue::sim::{Options, OPTIONS_USAGE};
+use rogue::time::SimClock;
+use rogue::resource::{Resources, ResourceKind};
+use rogue::needs::{Needs, Bed};
+use rogue::hq::{self, Headquarters};
+use rogue::skill::{Skills, SkillKind};
+
+// Runs the simulation without a window and dumps the worker state at the end.
+fn main() {
+    // --save is only for the headless runner, pull it out before the shared options see it
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let save_to = match args.iter().position(|arg| arg == "--save") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        },
+        Some(_) => bad_usage("--save needs a file".to_string()),
+        None => None,
+    };
+
+    let options = match Options::parse(args.into_iter()) {
+        Ok(options) => options,
+        Err(err) => bad_usage(err),
+    };
+    let (seed, frames) = (options.seed, options.frames);
+    let mut sim = match options.start() {
+        Ok(sim) => sim,
+        Err(err) => bad_usage(err),
+    };
+    sim.step_n(frames);
+
+    println!("seed {} after {} ticks", seed, sim.ecs.fetch::<SimClock>().now());
+    for kind in ResourceKind::ALL {
+        println!("stockpiled {}: {}", kind.name(), sim.ecs.fetch::<Resources>().get(kind));
+    }
+    {
+        let (workers, headquarters, beds) = (sim.ecs.read_storage::<Worker>(), sim.ecs.read_storage::<Headquarters>(), sim.ecs.read_storage::<Bed>());
+        println!("population {}/{}", workers.join().count(), hq::housing(headquarters.join().count(), beds.join().count()));
+    }
+    {
+        let (transforms, workers, needs, skills) = (sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<Worker>(), sim.ecs.read_storage::<Needs>(), sim.ecs.read_storage::<Skills>());
+        for (entity, transform, worker, needs, skills) in (&sim.ecs.entities(), &transforms, &workers, (&needs).maybe(), (&skills).maybe()).join() {
+            let task = match worker.task {
+                WorkerTask::Idle => "idle".to_string(),
+                WorkerTask::Mine(target) => format!("mine {}", target.id()),
+                WorkerTask::MoveTo(r, c) => format!("move to {},{}", r, c),
+                WorkerTask::Dig(r, c) => format!("dig {},{}", r, c),
+                WorkerTask::Build(target) => format!("build {}", target.id()),
+                WorkerTask::Attack(target) => format!("attack {}", target.id()),
+                WorkerTask::Eat => "eat".to_string(),
+                WorkerTask::Sleep => "sleep".to_string(),
+            };
+            let queued = match worker.queue.len() {
+                0 => String::new(),
+                n => format!(", {} queued", n),
+            };
+            let needs = needs.map_or(String::new(), |needs| format!(" hunger {} fatigue {}", needs.hunger, needs.fatigue));
+            let skills = skills.map_or(String::new(), |skills| SkillKind::ALL.iter()
+                .map(|&kind| format!(" {} {}", kind.name(), skills.level(kind)))
+                .collect());
+            println!("worker {} at {},{} ({}{}){}{}", entity.id(), transform.r, transform.c, task, queued, needs, skills);
+        }
+    }
+
+    if let Some(path) = save_to {
+        if let Err(err) = sim.save(&path) {
+            eprintln!("error saving to {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// bad arguments or a map or save that won't load
+fn bad_usage(err: String) -> ! {
+    eprintln!("{}", err);
+    eprintln!("usage: headless [--save SAVE_FILE] {}", OPTIONS_USAGE);
+    std::process::exit(1);
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use rltk::RGB;
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::System;
+use specs::{ReadExpect, WriteExpect, ReadStorage, WriteStorage, Entities};
+use specs::Join;
+
+use super::{Transform, MoveMap};
+use super::map::{Map, MapLoadError};
+use super::resource::{ResourceKind, Stockpile};
+use super::tile::{TileTypes, DataLine, data_lines, parse_glyph, parse_amount};
+use super::needs::Bed;
+use super::hq::Headquarters;
+use super::input::Selectable;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildResult {
+    Tile(String),// a tile type name
+    Stockpile,
+    Workshop,
+    Bed,
+    Headquarters,
+}
+
+#[derive(Clone)]
+pub struct BuildingType {
+    pub name: String,
+    pub ch: u16,
+    pub fg: RGB,
+    pub cost: Vec<(ResourceKind, u32)>,
+    pub work: u32,
+    pub result: BuildResult,
+}
+
+/// Everything that can be built, see `data/buildings.txt`.
+pub struct BuildingCatalog {
+    buildings: Vec<BuildingType>,
+}
+
+impl BuildingCatalog {
+    pub fn builtin() -> Self {
+        Self::parse(include_str!("../data/buildings.txt"), &TileTypes::builtin()).expect("built in buildings are broken")
+    }
+
+    /// Reads `[name]` sections of `key = value` lines, like the tile types. Tiles buildings
+    /// turn into have to be in `types`.
+    pub fn parse(text: &str, types: &TileTypes) -> Result<Self, MapLoadError> {
+        let mut buildings: Vec<(usize, BuildingType, Option<BuildResult>)> = vec![];
+
+        for line in data_lines(text) {
+            let (line_no, line) = line?;
+            let bad_line = |reason: String| MapLoadError::BadLine { line: line_no, reason };
+            let (key, value) = match line {
+                DataLine::Entry(key, value) => (key, value),
+                DataLine::Section(name) => {
+                    if buildings.iter().any(|(_, b, _)| b.name == name) {
+                        return Err(bad_line(format!("building [{}] is defined twice", name)));
+                    }
+                    buildings.push((line_no, BuildingType {
+                        name: name.to_string(),
+                        ch: '?' as u16,
+                        fg: RGB::named(rltk::WHITE),
+                        cost: vec![],
+                        work: 1,
+                        result: BuildResult::Stockpile,
+                    }, None));
+                    continue;
+                },
+            };
+            let (_, building, result) = buildings.last_mut().ok_or_else(|| bad_line("line outside of any building".to_string()))?;
+            if result.is_some() && (key == "tile" || key == "makes") {
+                return Err(bad_line(format!("[{}] already says what it turns into", building.name)));
+            }
+            match key {
+                "glyph" => building.ch = parse_glyph(value).map_err(bad_line)?,
+                "fg" => building.fg = RGB::from_hex(value).map_err(|_| bad_line(format!("fg must look like #rrggbb, got '{}'", value)))?,
+                "cost" => building.cost.push(parse_amount(key, value).map_err(bad_line)?),
+                "work" => building.work = value.parse::<u32>().map_err(|_| bad_line(format!("work must be a number, got '{}'", value)))?.max(1),
+                "tile" => match types.id(value) {
+                    Some(_) => *result = Some(BuildResult::Tile(value.to_string())),
+                    None => return Err(bad_line(format!("unknown tile type '{}'", value))),
+                },
+                "makes" => *result = Some(match value {
+                    "stockpile" => BuildResult::Stockpile,
+                    "workshop" => BuildResult::Workshop,
+                    "bed" => BuildResult::Bed,
+                    "headquarters" => BuildResult::Headquarters,
+                    _ => return Err(bad_line(format!("can't make '{}', only stockpile, workshop, bed or headquarters", value))),
+                }),
+                _ => return Err(bad_line(format!("unknown building key '{}'", key))),
+            }
+        }
+
+        let buildings = buildings.into_iter().map(|(line, mut building, result)| {
+            building.result = result.ok_or_else(|| MapLoadError::BadLine { line, reason: format!("[{}] needs a tile or makes key", building.name) })?;
+            Ok(building)
+        }).collect::<Result<Vec<_>, MapLoadError>>()?;
+        Ok(Self { buildings })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BuildingType> {
+        self.buildings.iter().find(|b| b.name == name)
+    }
+
+    pub fn all(&self) -> &[BuildingType] {
+        &self.buildings
+    }
+}
+
+/// A building waiting for its resources and for someone to put it up.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Blueprint {
+    pub building: String,
+    pub delivered: HashMap<ResourceKind, u32>,
+    pub progress: u32,// build actions done so far
+}
+
+impl Blueprint {
+    pub fn new(building: &str) -> Self {
+        Self { building: building.to_string(), delivered: HashMap::new(), progress: 0 }
+    }
+
+    /// Resources still to be brought over.
+    pub fn missing(&self, catalog: &BuildingCatalog) -> Vec<(ResourceKind, u32)> {
+        let cost = catalog.get(&self.building).map_or(&[][..], |b| &b.cost[..]);
+        cost.iter()
+            .map(|&(kind, amount)| (kind, amount.saturating_sub(self.delivered.get(&kind).copied().unwrap_or(0))))
+            .filter(|&(_, amount)| amount > 0)
+            .collect()
+    }
+
+    /// Takes up to `amount` of `kind` towards the cost, returns how much was used.
+    pub fn supply(&mut self, catalog: &BuildingCatalog, kind: ResourceKind, amount: u32) -> u32 {
+        let needed = self.missing(catalog).iter().find(|(k, _)| *k == kind).map_or(0, |&(_, n)| n);
+        let used = amount.min(needed);
+        *self.delivered.entry(kind).or_insert(0) += used;
+        used
+    }
+
+    pub fn is_supplied(&self, catalog: &BuildingCatalog) -> bool {
+        self.missing(catalog).is_empty()
+    }
+
+    pub fn is_done(&self, catalog: &BuildingCatalog) -> bool {
+        self.is_supplied(catalog) && catalog.get(&self.building).is_none_or(|b| self.progress >= b.work)
+    }
+}
+
+/// Where things get made. Doesn't do anything yet besides being in the way.
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Workshop;
+
+/// Turns finished blueprints into what they were for, once nobody stands on them.
+pub struct ConstructionManager;
+
+impl<'a> System<'a> for ConstructionManager {
+    type SystemData = ( Entities<'a>,
+                        WriteExpect<'a, Map>,
+                        ReadExpect<'a, MoveMap>,
+                        ReadExpect<'a, BuildingCatalog>,
+                        WriteStorage<'a, Transform>,
+                        ReadStorage<'a, Blueprint>,
+                        WriteStorage<'a, Stockpile>,
+                        WriteStorage<'a, Workshop>,
+                        WriteStorage<'a, Bed>,
+                        WriteStorage<'a, Headquarters>,
+                        WriteStorage<'a, Selectable>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, mut map, mmap, catalog, mut transforms, blueprints, mut stockpiles, mut workshops, mut beds, mut headquarters, mut selectables) = data;
+
+        let done: Vec<_> = (&entities, &transforms, &blueprints).join()
+            .filter(|(_, trans, blueprint)| blueprint.is_done(&catalog) && mmap.unit_at(trans.r as i32, trans.c as i32).is_none())
+            .map(|(entity, trans, blueprint)| (entity, trans.r, trans.c, blueprint.building.clone()))
+            .collect();
+        for (site, r, c, name) in done {
+            entities.delete(site).expect("deleting finished blueprint");
+            let building = match catalog.get(&name) {
+                Some(building) => building,
+                None => continue,
+            };
+            let (ch, color) = (building.ch, building.fg);
+            match &building.result {
+                BuildResult::Tile(tile) => if let Some(id) = map.types().id(tile) {
+                    map.set(r as usize, c as usize, id);
+                },
+                BuildResult::Stockpile => {
+                    entities.build_entity()
+                        .with(Transform { r, c, ch, color }, &mut transforms)
+                        .with(Stockpile, &mut stockpiles)
+                        .build();
+                },
+                BuildResult::Workshop => {
+                    entities.build_entity()
+                        .with(Transform { r, c, ch, color }, &mut transforms)
+                        .with(Workshop, &mut workshops)
+                        .build();
+                },
+                BuildResult::Bed => {
+                    entities.build_entity()
+                        .with(Transform { r, c, ch, color }, &mut transforms)
+                        .with(Bed, &mut beds)
+                        .build();
+                },
+                BuildResult::Headquarters => {
+                    entities.build_entity()
+                        .with(Transform { r, c, ch, color }, &mut transforms)
+                        .with(Selectable::new(), &mut selectables)
+                        .with(Headquarters::default(), &mut headquarters)
+                        .build();
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+/// Which part of the map is on screen. `r`, `c` is the world tile in the top left corner
+/// of the console, everything drawn or clicked goes through `to_screen`/`to_world`.
+pub struct Camera {
+    pub r: i32,
+    pub c: i32,
+    pub width: i32,
+    pub height: i32,
+    map_rows: i32,
+    map_cols: i32,
+}
+
+impl Camera {
+    pub fn new(width: i32, height: i32, map_rows: u32, map_cols: u32) -> Self {
+        Self { r: 0, c: 0, width, height, map_rows: map_rows as i32, map_cols: map_cols as i32 }
+    }
+
+    /// Screen position of a world tile, None when it is out of view.
+    pub fn to_screen(&self, r: u32, c: u32) -> Option<(i32, i32)> {
+        let (sr, sc) = (r as i32 - self.r, c as i32 - self.c);
+        if sr < 0 || sc < 0 || sr >= self.width || sc >= self.height {
+            return None;
+        }
+        Some((sr, sc))
+    }
+
+    /// World tile under a screen position, None when there is no map there.
+    pub fn to_world(&self, sr: i32, sc: i32) -> Option<(u32, u32)> {
+        let (r, c) = (sr + self.r, sc + self.c);
+        if sr < 0 || sc < 0 || sr >= self.width || sc >= self.height
+            || r < 0 || c < 0 || r >= self.map_rows || c >= self.map_cols {
+            return None;
+        }
+        Some((r as u32, c as u32))
+    }
+
+    /// Like `to_world`, but anything off the map snaps to the nearest tile on it.
+    pub fn to_world_clamped(&self, sr: i32, sc: i32) -> (u32, u32) {
+        let r = (sr.clamp(0, self.width - 1) + self.r).clamp(0, self.map_rows - 1);
+        let c = (sc.clamp(0, self.height - 1) + self.c).clamp(0, self.map_cols - 1);
+        (r as u32, c as u32)
+    }
+
+    pub fn scroll(&mut self, dr: i32, dc: i32) {
+        self.r += dr;
+        self.c += dc;
+        self.clamp();
+    }
+
+    pub fn center_on(&mut self, r: u32, c: u32) {
+        self.r = r as i32 - self.width / 2;
+        self.c = c as i32 - self.height / 2;
+        self.clamp();
+    }
+
+    // keeps the view on the map, maps smaller than the screen stay in the corner
+    fn clamp(&mut self) {
+        self.r = self.r.min(self.map_rows - self.width).max(0);
+        self.c = self.c.min(self.map_cols - self.height).max(0);
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::System;
+use specs::{ReadStorage, Entities};
+use specs::Join;
+
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Health {
+    pub hp: i32,
+    pub max_hp: i32,
+}
+
+impl Health {
+    pub fn new(max_hp: i32) -> Self {
+        Self { hp: max_hp, max_hp }
+    }
+    pub fn is_dead(&self) -> bool {
+        self.hp <= 0
+    }
+}
+
+/// Who is on whose side. Units only fight the other side and only make way for their own.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub enum Faction {
+    Player,
+    Hostile,
+}
+
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct CombatStats {
+    pub attack: i32,
+    pub defense: i32,
+}
+
+/// Damage of one hit, every hit does at least a little.
+pub fn damage(attacker: &CombatStats, defender: Option<&CombatStats>) -> i32 {
+    (attacker.attack - defender.map_or(0, |stats| stats.defense)).max(1)
+}
+
+/// Removes everything that ran out of health.
+pub struct DeathSystem;
+
+impl<'a> System<'a> for DeathSystem {
+    type SystemData = ( Entities<'a>,
+                        ReadStorage<'a, Health>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, healths) = data;
+        for (entity, health) in (&entities, &healths).join() {
+            if health.is_dead() {
+                entities.delete(entity).expect("deleting dead entity");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{WorldExt, Join, Entity};
+
+    use super::*;
+    use crate::{Worker, WorkerTask};
+    use crate::ai::Monster;
+    use crate::sim::Simulation;
+
+    // a worker with a monster right next to it
+    const ARENA: &str = "[meta]\nspawn = 1,1\n[legend]\n. = floor\nw = wall\ng = monster\n[map]\nwwwwww\nw.g..w\nwwwwww\n";
+
+    fn first<T: Component>(sim: &Simulation) -> Entity {
+        (&sim.ecs.entities(), &sim.ecs.read_storage::<T>()).join().next().unwrap().0
+    }
+
+    fn hp(sim: &Simulation, entity: Entity) -> i32 {
+        sim.ecs.read_storage::<Health>().get(entity).unwrap().hp
+    }
+
+    #[test]
+    fn hits_hurt_and_kill() {
+        let mut sim = Simulation::from_text(0, ARENA);
+        let (worker, monster) = (first::<Worker>(&sim), first::<Monster>(&sim));
+        let full = hp(&sim, monster);
+        let hit = {
+            let stats = sim.ecs.read_storage::<CombatStats>();
+            damage(stats.get(worker).unwrap(), stats.get(monster))
+        };
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::Attack(monster));
+        for _ in 0..100 {
+            if hp(&sim, monster) < full {
+                break;
+            }
+            sim.step();
+        }
+        assert_eq!(hp(&sim, monster), full - hit);
+
+        sim.ecs.write_storage::<Health>().get_mut(monster).unwrap().hp = hit;
+        sim.step_n(100);
+        assert!(!sim.ecs.is_alive(monster), "still standing at 0 health");
+        assert!(hp(&sim, worker) < sim.ecs.read_storage::<Health>().get(worker).unwrap().max_hp, "monster never hit back");
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::System;
+use specs::{Read, Write, ReadExpect, ReadStorage, WriteStorage, LazyUpdate};
+use specs::{WorldExt, Join};
+
+use super::{Transform, Worker, WorkerTask, MoveMap};
+use super::time::{SimClock, TICKS_PER_SECOND};
+use super::resource::{ResourceKind, Resources};
+use super::needs::Bed;
+use super::sim::create_worker;
+
+/// What a new worker costs, paid from the stockpiles when its training starts.
+pub const TRAIN_COST: [(ResourceKind, u32); 2] = [(ResourceKind::Food, 5), (ResourceKind::Gold, 10)];
+const TRAIN_TICKS: u64 = 20 * TICKS_PER_SECOND;
+// more orders than this are ignored
+const MAX_QUEUED: u32 = 5;
+// room for this many workers per headquarters, plus one per bed
+const HQ_HOUSING: usize = 4;
+const BED_HOUSING: usize = 1;
+
+/// Trains new workers one after another. They come out next to it and head to the rally point.
+#[derive(Component, Debug, Clone, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Headquarters {
+    pub queued: u32,// workers ordered, including the one in training
+    pub training_since: Option<u64>,// tick the current one was paid for
+    pub rally: Option<(u32, u32)>,
+}
+
+impl Headquarters {
+    pub fn queue(&mut self) {
+        self.queued = (self.queued + 1).min(MAX_QUEUED);
+    }
+    /// How far along the current worker is, in percent.
+    pub fn progress(&self, now: u64) -> Option<u64> {
+        self.training_since.map(|since| (now.saturating_sub(since) * 100 / TRAIN_TICKS).min(100))
+    }
+}
+
+/// How many workers there is room for.
+pub fn housing(headquarters: usize, beds: usize) -> usize {
+    headquarters * HQ_HOUSING + beds * BED_HOUSING
+}
+
+/// Starts training when there is room and the stockpiles can pay for it, and lets
+/// trained workers out.
+pub struct RecruitManager;
+
+impl<'a> System<'a> for RecruitManager {
+    type SystemData = ( Read<'a, SimClock>,
+                        Write<'a, Resources>,
+                        ReadExpect<'a, MoveMap>,
+                        Read<'a, LazyUpdate>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, Worker>,
+                        ReadStorage<'a, Bed>,
+                        WriteStorage<'a, Headquarters>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (clock, mut resources, mmap, lazy, transforms, workers, beds, mut headquarters) = data;
+        let now = clock.now();
+
+        let room = housing(headquarters.join().count(), beds.join().count());
+        // the ones in training already have their room taken
+        let mut population = workers.join().count() + headquarters.join().filter(|hq| hq.training_since.is_some()).count();
+        let mut taken = HashSet::new();
+
+        for (hq, trans) in (&mut headquarters, &transforms).join() {
+            match hq.training_since {
+                None if hq.queued > 0 && population < room
+                    && TRAIN_COST.iter().all(|&(kind, amount)| resources.get(kind) >= amount) => {
+                    for (kind, amount) in TRAIN_COST {
+                        resources.take(kind, amount);
+                    }
+                    hq.training_since = Some(now);
+                    population += 1;
+                },
+                Some(since) if now >= since + TRAIN_TICKS => {
+                    // waits for a free spot next to it
+                    let exit = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)].iter()
+                        .map(|(dr, dc)| (trans.r as i32 + dr, trans.c as i32 + dc))
+                        .find(|&(r, c)| mmap.is_walkable(r, c) && mmap.unit_at(r, c).is_none()
+                            && mmap.reserved_by(r, c).is_none() && !taken.contains(&(r, c)));
+                    if let Some((r, c)) = exit {
+                        taken.insert((r, c));
+                        hq.queued = hq.queued.saturating_sub(1);
+                        hq.training_since = None;
+                        let rally = hq.rally;
+                        lazy.exec_mut(move |world| {
+                            let worker = create_worker(world, r as u32, c as u32);
+                            if let (Some((rally_r, rally_c)), Some(worker)) = (rally, world.write_storage::<Worker>().get_mut(worker)) {
+                                worker.order(WorkerTask::MoveTo(rally_r, rally_c));
+                            }
+                        });
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Simulation;
+
+    // one worker, a headquarters and a stockpile to eat from
+    const BASE: &str = "[meta]\nspawn = 1,1\n[legend]\n. = floor\nw = wall\nH = headquarters\ns = stockpile\n[map]\nwwwwwwwwww\nw........w\nw..H..s..w\nw........w\nwwwwwwwwww\n";
+
+    #[test]
+    fn training_stops_at_the_housing_cap() {
+        let mut sim = Simulation::from_text(0, BASE);
+        for (kind, _) in TRAIN_COST {
+            sim.ecs.write_resource::<Resources>().add(kind, 1000);
+        }
+        let before = sim.ecs.fetch::<Resources>().get(ResourceKind::Gold);
+        for hq in (&mut sim.ecs.write_storage::<Headquarters>()).join() {
+            for _ in 0..MAX_QUEUED {
+                hq.queue();
+            }
+        }
+        // long enough to train everything that was ordered
+        sim.step_n((MAX_QUEUED as u64 * TRAIN_TICKS / crate::time::TICKS_PER_FRAME) as u32 + 100);
+
+        let trained = HQ_HOUSING - 1;
+        assert_eq!(sim.ecs.read_storage::<Worker>().join().count(), HQ_HOUSING);
+        let hq = sim.ecs.read_storage::<Headquarters>().join().next().cloned().unwrap();
+        assert_eq!(hq.queued, MAX_QUEUED - trained as u32);
+        assert_eq!(hq.training_since, None, "training one with no room for it");
+        let cost = TRAIN_COST.iter().find(|(kind, _)| *kind == ResourceKind::Gold).unwrap().1;
+        assert_eq!(sim.ecs.fetch::<Resources>().get(ResourceKind::Gold), before - cost * trained as u32);
+    }
+}
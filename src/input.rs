@@ -1,25 +1,61 @@
 use specs::System;
-use specs::{Read, Write, ReadStorage, WriteStorage};
+use specs::{Read, Write, ReadExpect, ReadStorage, WriteStorage};
 use specs::{Component, VecStorage};
-use specs::{Entity};
+use serde::{Serialize, Deserialize};
+use specs::{Entity, Entities};
 use specs::{Join};
 
-use super::{Transform, Worker, WorkerTask};
+use super::{Transform, Worker, WorkerTask, MoveMap};
+use super::map::Map;
+use super::job::{JobBoard, JobKind};
+use super::build::{Blueprint, BuildingCatalog};
+use super::combat::Faction;
+use super::path::formation;
+use super::resource::{ResourceDeposit, Resources};
+use super::hq::Headquarters;
 
-use specs::{World, WorldExt};
+use specs::World;
 use specs::{RunNow};
 
 pub fn run_systems(ecs: &World) {
     let mut mh = MouseHandler{};
+    let mut hh = HeadquartersInputHandler{};
+    let mut gh = GroupHandler{};
     let mut wih = WorkerInputHandler{};
-    mh.run_now(&ecs);
-    wih.run_now(&ecs);
+    let mut dh = DesignationHandler{};
+    let mut bh = BlueprintHandler{};
+    mh.run_now(ecs);
+    hh.run_now(ecs);
+    gh.run_now(ecs);
+    dh.run_now(ecs);
+    bh.run_now(ecs);
+    wih.run_now(ecs);
 }
 
-#[derive(Component)]
+pub const CONTROL_GROUPS: u8 = 9;
+// blueprints are drawn in a darker shade of the finished building
+const BLUEPRINT_DIM: f32 = 0.6;
+
+#[derive(Component, Clone, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct Selectable{
     pub selected: bool,
+    pub groups: u16,// bit n set when in control group n
+}
+
+impl Selectable{
+    pub fn new() -> Self {
+        Self { selected: false, groups: 0 }
+    }
+    pub fn in_group(&self, group: u8) -> bool {
+        self.groups & (1 << group) != 0
+    }
+}
+
+impl Default for Selectable{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Default)]
@@ -28,13 +64,35 @@ pub struct MouseEvent(pub MouseEventT);
 pub enum MouseEventT{
     #[default]
     Empty,
-    BoxSelect(u32, u32, u32, u32),// r, c, w, h
+    BoxSelect(u32, u32, u32, u32),// r, c of two opposite corners, inclusive
+    AddBoxSelect(u32, u32, u32, u32),// like BoxSelect but keeps the current selection
+    ClickSelect(u32, u32),// r, c, selects one unit there and nothing else
+    ToggleSelect(Entity),
     MoveTo(u32, u32),// r, c
-    Activate(Entity),// r, c
+    Activate(Entity),
+    Dig(u32, u32),// r, c of a wall for the selected workers to dig out
+    DesignateDig(u32, u32, u32, u32),// posts jobs for every wall and deposit in the box, corners like BoxSelect
+    CancelDig(u32, u32, u32, u32),
+    PlaceBlueprint(usize, u32, u32, u32, u32),// catalog index, then a box like DesignateDig
+    CancelBlueprint(u32, u32, u32, u32),
+}
+
+#[derive(Default)]
+pub struct KeyEvent(pub KeyEventT);
+#[derive(Default, Debug)]
+pub enum KeyEventT{
+    #[default]
+    Empty,
+    AssignGroup(u8),// replaces the group with the current selection
+    RecallGroup(u8),// selects exactly the group
+    Train,// one more worker from every selected headquarters
 }
 
 #[derive(Default)]
 pub struct IsSomeSelected(pub bool);
+/// Set while shift is held, orders then go to the back of the queue instead of replacing it.
+#[derive(Default)]
+pub struct QueueOrders(pub bool);
 pub struct MouseHandler;
 
 impl<'a> System<'a> for MouseHandler{
@@ -48,51 +106,337 @@ impl<'a> System<'a> for MouseHandler{
         let MouseEvent(event) = &*mouse_event;
 
         match *event {
-            MouseEventT::BoxSelect(select_r, select_c, select_w, select_h) => {
-                *some_selected = IsSomeSelected(false);
+            MouseEventT::BoxSelect(r0, c0, r1, c1) | MouseEventT::AddBoxSelect(r0, c0, r1, c1) => {
+                let add = matches!(event, MouseEventT::AddBoxSelect(..));
+                let (rows, cols) = (r0.min(r1)..=r0.max(r1), c0.min(c1)..=c0.max(c1));
                 for (transform, select) in (&trans, &mut selectable).join() {
-                    if transform.r >= select_r && transform.r <= select_r + select_w
-                        && transform.c >= select_c && transform.c <= select_c + select_h {
-                            select.selected = true;
-                            *some_selected = IsSomeSelected(true);
-                    }
-                    else {
-                        select.selected = false;
+                    let inside = rows.contains(&transform.r) && cols.contains(&transform.c);
+                    select.selected = inside || (add && select.selected);
+                }
+            },
+            MouseEventT::ClickSelect(r, c) => {
+                let mut found = false;
+                for (transform, select) in (&trans, &mut selectable).join() {
+                    select.selected = !found && transform.r == r && transform.c == c;
+                    found |= select.selected;
+                }
+            },
+            MouseEventT::ToggleSelect(entity) => {
+                if let Some(select) = selectable.get_mut(entity) {
+                    select.selected = !select.selected;
+                }
+            },
+            _ => return,
+        }
+        *some_selected = IsSomeSelected((&selectable).join().any(|s| s.selected));
+        *mouse_event = MouseEvent(MouseEventT::Empty);
+    }
+}
+
+pub struct GroupHandler;
+
+impl<'a> System<'a> for GroupHandler{
+    type SystemData = ( Write<'a, KeyEvent>,
+                        Write<'a, IsSomeSelected>,
+                        WriteStorage<'a, Selectable>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (mut key_event, mut some_selected, mut selectable) = data;
+        let KeyEvent(event) = std::mem::take(&mut *key_event);
+
+        match event {
+            KeyEventT::AssignGroup(group) if group < CONTROL_GROUPS => {
+                for select in (&mut selectable).join() {
+                    if select.selected {
+                        select.groups |= 1 << group;
+                    } else {
+                        select.groups &= !(1 << group);
                     }
                 }
-                *mouse_event = MouseEvent(MouseEventT::Empty);
+            },
+            // an empty group leaves the selection alone
+            KeyEventT::RecallGroup(group) if group < CONTROL_GROUPS && (&selectable).join().any(|s| s.in_group(group)) => {
+                for select in (&mut selectable).join() {
+                    select.selected = select.in_group(group);
+                }
+                *some_selected = IsSomeSelected(true);
             },
             _ => {},
         }
     }
 }
 
+/// Queues up workers at the selected headquarters and moves their rally point to where
+/// orders go. Orders are left for the workers, they can be selected at the same time.
+pub struct HeadquartersInputHandler;
+
+impl<'a> System<'a> for HeadquartersInputHandler{
+    type SystemData = ( Read<'a, MouseEvent>,
+                        Write<'a, KeyEvent>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, Selectable>,
+                        WriteStorage<'a, Headquarters>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (mouse_event, mut key_event, transforms, selectable, mut headquarters) = data;
+        let rally = match mouse_event.0 {
+            MouseEventT::MoveTo(r, c) | MouseEventT::Dig(r, c) => Some((r, c)),
+            MouseEventT::Activate(entity) => transforms.get(entity).map(|trans| (trans.r, trans.c)),
+            _ => None,
+        };
+        let train = matches!(key_event.0, KeyEventT::Train);
+        if train {
+            *key_event = KeyEvent(KeyEventT::Empty);
+        }
+
+        for (hq, select) in (&mut headquarters, &selectable).join() {
+            if !select.selected {
+                continue;
+            }
+            if rally.is_some() {
+                hq.rally = rally;
+            }
+            if train {
+                hq.queue();
+            }
+        }
+    }
+}
+
+/// Posts and takes down dig and mine jobs for everything explored in a box.
+pub struct DesignationHandler;
+
+impl<'a> System<'a> for DesignationHandler{
+    type SystemData = ( Entities<'a>,
+                        Write<'a, MouseEvent>,
+                        Write<'a, JobBoard>,
+                        ReadExpect<'a, Map>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, ResourceDeposit>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (entities, mut mouse_event, mut board, map, transforms, deposits) = data;
+        let (r0, c0, r1, c1, designate) = match mouse_event.0 {
+            MouseEventT::DesignateDig(r0, c0, r1, c1) => (r0, c0, r1, c1, true),
+            MouseEventT::CancelDig(r0, c0, r1, c1) => (r0, c0, r1, c1, false),
+            _ => return,
+        };
+        let (rows, cols) = (r0.min(r1)..=r0.max(r1), c0.min(c1)..=c0.max(c1));
+        for r in rows.clone() {
+            for c in cols.clone() {
+                match designate {
+                    true if map.is_revealed(r, c) && map.at(r, c).minable => board.post(JobKind::Dig(r, c)),
+                    true => {},
+                    false => board.cancel(JobKind::Dig(r, c)),
+                }
+            }
+        }
+        for (entity, trans, _) in (&entities, &transforms, &deposits).join() {
+            if !rows.contains(&trans.r) || !cols.contains(&trans.c) {
+                continue;
+            }
+            // piles already have a haul job of their own
+            match designate {
+                true if map.is_revealed(trans.r, trans.c) && !board.is_posted(JobKind::Haul(entity)) => board.post(JobKind::Mine(entity)),
+                true => {},
+                false => {
+                    board.cancel(JobKind::Mine(entity));
+                    board.cancel(JobKind::Haul(entity));
+                },
+            }
+        }
+        *mouse_event = MouseEvent(MouseEventT::Empty);
+    }
+}
+
+/// Puts down blueprints on every free explored tile in a box, or takes them away again.
+pub struct BlueprintHandler;
+
+impl<'a> System<'a> for BlueprintHandler{
+    type SystemData = ( Entities<'a>,
+                        Write<'a, MouseEvent>,
+                        Write<'a, JobBoard>,
+                        Write<'a, Resources>,
+                        ReadExpect<'a, BuildingCatalog>,
+                        ReadExpect<'a, Map>,
+                        ReadExpect<'a, MoveMap>,
+                        WriteStorage<'a, Transform>,
+                        WriteStorage<'a, Blueprint>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (entities, mut mouse_event, mut board, mut resources, catalog, map, mmap, mut transforms, mut blueprints) = data;
+        match mouse_event.0 {
+            MouseEventT::PlaceBlueprint(index, r0, c0, r1, c1) => {
+                let building = match catalog.all().get(index) {
+                    Some(building) => building,
+                    None => return,
+                };
+                for r in r0.min(r1)..=r0.max(r1) {
+                    for c in c0.min(c1)..=c0.max(c1) {
+                        // is_walkable also keeps out deposits, stockpiles and other blueprints
+                        if !map.is_revealed(r, c) || !mmap.is_walkable(r as i32, c as i32) {
+                            continue;
+                        }
+                        let site = entities.build_entity()
+                            .with(Transform { r, c, ch: building.ch, color: building.fg * BLUEPRINT_DIM }, &mut transforms)
+                            .with(Blueprint::new(&building.name), &mut blueprints)
+                            .build();
+                        board.post(JobKind::Build(site));
+                    }
+                }
+            },
+            MouseEventT::CancelBlueprint(r0, c0, r1, c1) => {
+                let (rows, cols) = (r0.min(r1)..=r0.max(r1), c0.min(c1)..=c0.max(c1));
+                for (site, trans, blueprint) in (&entities, &transforms, &blueprints).join() {
+                    if rows.contains(&trans.r) && cols.contains(&trans.c) {
+                        // whatever was brought over goes back into store
+                        for (&kind, &amount) in blueprint.delivered.iter() {
+                            resources.add(kind, amount);
+                        }
+                        board.cancel(JobKind::Build(site));
+                        entities.delete(site).expect("deleting cancelled blueprint");
+                    }
+                }
+            },
+            _ => return,
+        }
+        *mouse_event = MouseEvent(MouseEventT::Empty);
+    }
+}
+
 pub struct WorkerInputHandler;
 impl<'a> System<'a> for WorkerInputHandler{
     type SystemData = ( Write<'a, MouseEvent>,
                         Read<'a, IsSomeSelected>,
+                        Read<'a, QueueOrders>,
+                        ReadExpect<'a, MoveMap>,
                         WriteStorage<'a, Worker>,
-                        ReadStorage<'a, Selectable>);
+                        ReadStorage<'a, Selectable>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, ResourceDeposit>,
+                        ReadStorage<'a, Faction>);
 
     fn run(&mut self, data: Self::SystemData){
-        let (mut mouse_event, some_selected,mut workers, selectable) = data;
+        let (mut mouse_event, some_selected, queue, mmap, mut workers, selectable, transforms, deposits, factions) = data;
+        // orders are consumed here, otherwise a stale order keeps overriding the task
+        let MouseEvent(event) = std::mem::take(&mut *mouse_event);
         if let IsSomeSelected(false) = *some_selected {
             return;
         }
-        let MouseEvent(event) = &*mouse_event;
 
-        for (worker, select) in (&mut workers, &selectable).join() {
-            if select.selected {
-                match *event {
-                    MouseEventT::Activate(entity) => {
-                        worker.task = WorkerTask::Mine(entity);
-                    },
-                    MouseEventT::MoveTo(r, c) => {
-                        worker.task = WorkerTask::MoveTo(r, c);
-                    },
-                    _ => {},
+        let task = match event {
+            MouseEventT::Activate(entity) => {
+                if factions.get(entity) == Some(&Faction::Hostile) {
+                    WorkerTask::Attack(entity)
+                } else if deposits.contains(entity) {
+                    WorkerTask::Mine(entity)
+                } else if let Some(trans) = transforms.get(entity) {
+                    WorkerTask::MoveTo(trans.r, trans.c)
+                } else {
+                    return;
                 }
+            },
+            MouseEventT::MoveTo(r, c) => WorkerTask::MoveTo(r, c),
+            MouseEventT::Dig(r, c) => WorkerTask::Dig(r, c),
+            _ => return,
+        };
+
+        let starts: Vec<(u32, u32)> = (&workers, &selectable, &transforms).join()
+            .filter(|(_, select, _)| select.selected)
+            .map(|(_, _, trans)| (trans.r, trans.c))
+            .collect();
+        // a group sent somewhere spreads out instead of fighting over one tile
+        let mut destinations = match task {
+            WorkerTask::MoveTo(r, c) => formation(&mmap, (r, c), &starts).into_iter(),
+            _ => vec![].into_iter(),
+        };
+        for (worker, _, _) in (&mut workers, &selectable, &transforms).join().filter(|(_, select, _)| select.selected) {
+            let task = match (task, destinations.next()) {
+                (WorkerTask::MoveTo(..), Some((r, c))) => WorkerTask::MoveTo(r, c),
+                _ => task,
+            };
+            if queue.0 {
+                worker.queue_order(task);
+            } else {
+                worker.order(task);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use specs::{WorldExt, Builder};
+
+    use super::*;
+
+    // units at these tiles, nothing selected
+    fn world_with_units(at: &[(u32, u32)]) -> (World, Vec<Entity>) {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<Selectable>();
+        world.insert(MouseEvent::default());
+        world.insert(KeyEvent::default());
+        world.insert(IsSomeSelected::default());
+        let units = at.iter().map(|&(r, c)| world.create_entity()
+            .with(Transform { r, c, ch: '@' as u16, color: rltk::RGB::named(rltk::RED) })
+            .with(Selectable::new())
+            .build()).collect();
+        (world, units)
+    }
+
+    fn selected(world: &World, units: &[Entity]) -> Vec<bool> {
+        let selectable = world.read_storage::<Selectable>();
+        units.iter().map(|&unit| selectable.get(unit).unwrap().selected).collect()
+    }
+
+    fn groups(world: &World, units: &[Entity]) -> Vec<u16> {
+        let selectable = world.read_storage::<Selectable>();
+        units.iter().map(|&unit| selectable.get(unit).unwrap().groups).collect()
+    }
+
+    fn mouse(world: &mut World, event: MouseEventT) {
+        *world.write_resource::<MouseEvent>() = MouseEvent(event);
+        MouseHandler.run_now(world);
+    }
+
+    fn key(world: &mut World, event: KeyEventT) {
+        *world.write_resource::<KeyEvent>() = KeyEvent(event);
+        GroupHandler.run_now(world);
+    }
+
+    #[test]
+    fn box_select_picks_whats_inside() {
+        let (mut world, units) = world_with_units(&[(2, 2), (3, 2), (4, 5), (5, 6), (6, 4), (4, 7)]);
+        // corners in either order
+        mouse(&mut world, MouseEventT::BoxSelect(5, 6, 3, 2));
+        assert_eq!(selected(&world, &units), [false, true, true, true, false, false]);
+        assert!(world.fetch::<IsSomeSelected>().0);
+
+        mouse(&mut world, MouseEventT::AddBoxSelect(6, 4, 6, 4));
+        assert_eq!(selected(&world, &units), [false, true, true, true, true, false]);
+        mouse(&mut world, MouseEventT::BoxSelect(0, 0, 1, 1));
+        assert_eq!(selected(&world, &units), [false; 6]);
+        assert!(!world.fetch::<IsSomeSelected>().0);
+    }
+
+    #[test]
+    fn groups_are_assigned_and_recalled() {
+        let (mut world, units) = world_with_units(&[(1, 1), (2, 1), (3, 1)]);
+        mouse(&mut world, MouseEventT::BoxSelect(1, 1, 2, 1));
+        key(&mut world, KeyEventT::AssignGroup(2));
+        mouse(&mut world, MouseEventT::BoxSelect(2, 1, 3, 1));
+        key(&mut world, KeyEventT::AssignGroup(4));
+        assert_eq!(groups(&world, &units), [1 << 2, 1 << 2 | 1 << 4, 1 << 4]);
+
+        key(&mut world, KeyEventT::RecallGroup(2));
+        assert_eq!(selected(&world, &units), [true, true, false]);
+        // reassigning takes out whoever isn't selected anymore
+        mouse(&mut world, MouseEventT::ClickSelect(3, 1));
+        key(&mut world, KeyEventT::AssignGroup(2));
+        assert_eq!(groups(&world, &units), [0, 1 << 4, 1 << 2 | 1 << 4]);
+        // nobody in it, nothing changes
+        key(&mut world, KeyEventT::RecallGroup(7));
+        assert_eq!(selected(&world, &units), [false, false, true]);
+    }
+}
@@ -0,0 +1,278 @@
+use serde::{Serialize, Deserialize};
+use specs::saveload::{ConvertSaveload, Marker};
+use specs::error::NoError;
+use specs::System;
+use specs::{Read, Write, ReadExpect, ReadStorage, WriteStorage, Entity, Entities};
+use specs::Join;
+
+use super::{Transform, Worker, WorkerTask, MoveMap};
+use super::map::Map;
+use super::path::distance;
+use super::time::{Actor, ActionFailure, SimClock};
+use super::resource::{ResourceDeposit, Stockpile, Resources};
+use super::build::{Blueprint, BuildingCatalog};
+
+// a job someone couldn't get to stays off the board for this long
+const RETRY_TICKS: u64 = 5 * super::time::TICKS_PER_SECOND;
+
+/// Something the player wants done, without saying by whom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    Dig(u32, u32),// a minable tile
+    Mine(Entity),// a deposit
+    Haul(Entity),// a pile left on the floor, picked up the same way as mining
+    Build(Entity),// a blueprint
+}
+
+impl JobKind {
+    /// What a worker does to get the job done.
+    pub fn task(&self) -> WorkerTask {
+        match *self {
+            JobKind::Dig(r, c) => WorkerTask::Dig(r, c),
+            JobKind::Mine(entity) | JobKind::Haul(entity) => WorkerTask::Mine(entity),
+            JobKind::Build(entity) => WorkerTask::Build(entity),
+        }
+    }
+}
+
+pub struct Job {
+    pub kind: JobKind,
+    pub claimed_by: Option<Entity>,
+    retry_at: u64,// tick it can be claimed again after someone gave up on it
+}
+
+/// Every posted job, idle workers are handed the nearest one nobody has claimed yet.
+#[derive(Default)]
+pub struct JobBoard {
+    jobs: Vec<Job>,
+}
+
+impl JobBoard {
+    /// Adds a job unless it's already up.
+    pub fn post(&mut self, kind: JobKind) {
+        if !self.is_posted(kind) {
+            self.jobs.push(Job { kind, claimed_by: None, retry_at: 0 });
+        }
+    }
+    pub fn cancel(&mut self, kind: JobKind) {
+        self.jobs.retain(|job| job.kind != kind);
+    }
+    pub fn is_posted(&self, kind: JobKind) -> bool {
+        self.jobs.iter().any(|job| job.kind == kind)
+    }
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+}
+
+// Saved form of the board, entities are replaced by their save markers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobBoardData<M> {
+    jobs: Vec<JobData<M>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobData<M> {
+    kind: JobKindData<M>,
+    claimed_by: Option<M>,
+    retry_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum JobKindData<M> {
+    Dig(u32, u32),
+    Mine(M),
+    Haul(M),
+    Build(M),
+}
+
+impl<M: Marker> ConvertSaveload<M> for JobBoard {
+    type Data = JobBoardData<M>;
+    type Error = NoError;
+
+    fn convert_into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+    where
+        F: FnMut(Entity) -> Option<M>,
+    {
+        // jobs on things that are already gone are dropped
+        let jobs = self.jobs.iter().filter_map(|job| {
+            let kind = match job.kind {
+                JobKind::Dig(r, c) => JobKindData::Dig(r, c),
+                JobKind::Mine(target) => JobKindData::Mine(ids(target)?),
+                JobKind::Haul(target) => JobKindData::Haul(ids(target)?),
+                JobKind::Build(target) => JobKindData::Build(ids(target)?),
+            };
+            Some(JobData { kind, claimed_by: job.claimed_by.and_then(&mut ids), retry_at: job.retry_at })
+        }).collect();
+        Ok(JobBoardData { jobs })
+    }
+
+    fn convert_from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+    where
+        F: FnMut(M) -> Option<Entity>,
+    {
+        let jobs = data.jobs.into_iter().filter_map(|job| {
+            let kind = match job.kind {
+                JobKindData::Dig(r, c) => JobKind::Dig(r, c),
+                JobKindData::Mine(target) => JobKind::Mine(ids(target)?),
+                JobKindData::Haul(target) => JobKind::Haul(ids(target)?),
+                JobKindData::Build(target) => JobKind::Build(ids(target)?),
+            };
+            Some(Job { kind, claimed_by: job.claimed_by.and_then(&mut ids), retry_at: job.retry_at })
+        }).collect();
+        Ok(JobBoard { jobs })
+    }
+}
+
+/// Clears finished jobs, takes back claims from workers that moved on and hands out
+/// open jobs to idle workers.
+pub struct JobAssigner;
+
+impl<'a> System<'a> for JobAssigner {
+    type SystemData = ( Entities<'a>,
+                        Read<'a, SimClock>,
+                        Write<'a, JobBoard>,
+                        ReadExpect<'a, Map>,
+                        ReadExpect<'a, MoveMap>,
+                        Read<'a, Resources>,
+                        ReadExpect<'a, BuildingCatalog>,
+                        ReadStorage<'a, Blueprint>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, ResourceDeposit>,
+                        ReadStorage<'a, Stockpile>,
+                        ReadStorage<'a, Actor>,
+                        WriteStorage<'a, Worker>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, clock, mut board, map, mmap, resources, catalog, blueprints, transforms, deposits, stockpiles, actors, mut workers) = data;
+        let now = clock.now();
+
+        // a job is done once there is nothing left to do there
+        board.jobs.retain(|job| match job.kind {
+            JobKind::Dig(r, c) => map.at(r, c).minable,
+            JobKind::Mine(entity) | JobKind::Haul(entity) => entities.is_alive(entity) && deposits.contains(entity),
+            JobKind::Build(entity) => entities.is_alive(entity) && blueprints.contains(entity),
+        });
+
+        for job in board.jobs.iter_mut() {
+            let claimant = match job.claimed_by {
+                Some(claimant) => claimant,
+                None => continue,
+            };
+            // redirected by the player, or didn't make it. Put off for a meal or a nap still counts,
+            // it's picked up again from the queue.
+            let task = job.kind.task();
+            let (on_it, put_off) = workers.get(claimant)
+                .map_or((false, false), |worker| (worker.task == task, worker.queue.contains(&task)));
+            let stuck = on_it && matches!(actors.get(claimant).and_then(|actor| actor.failure()), Some(ActionFailure::Unreachable(..)));
+            if stuck {
+                job.retry_at = now + RETRY_TICKS;
+            }
+            if stuck || !(on_it || put_off) {
+                job.claimed_by = None;
+            }
+        }
+
+        // hauling needs somewhere to haul to
+        let can_haul = (&stockpiles).join().next().is_some();
+        // only tiles with open ground next to them can be worked on
+        let exposed = |(r, c): (u32, u32)| [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+            .any(|(dr, dc)| mmap.is_walkable(r as i32 + dr, c as i32 + dc));
+        let location = |kind: JobKind| match kind {
+            JobKind::Dig(r, c) => exposed((r, c)).then_some((r, c)),
+            JobKind::Mine(entity) | JobKind::Haul(entity) => transforms.get(entity)
+                .filter(|_| can_haul)
+                .map(|trans| (trans.r, trans.c)),
+            // worth starting once the stockpiles hold everything it still needs
+            JobKind::Build(entity) => transforms.get(entity)
+                .filter(|_| blueprints.get(entity).is_some_and(|blueprint| blueprint.missing(&catalog).iter()
+                    .all(|&(kind, amount)| resources.get(kind) >= amount) && (blueprint.is_supplied(&catalog) || can_haul)))
+                .map(|trans| (trans.r, trans.c)),
+        };
+
+        for (entity, worker, trans) in (&entities, &mut workers, &transforms).join() {
+            if !matches!(worker.task, WorkerTask::Idle) || !worker.queue.is_empty() {
+                continue;
+            }
+            let pos = (trans.r, trans.c);
+            let nearest = board.jobs.iter_mut()
+                .filter(|job| job.claimed_by.is_none() && job.retry_at <= now)
+                .filter_map(|job| location(job.kind).map(|at| (job, at)))
+                .min_by_key(|(_, at)| distance(pos, *at));
+            if let Some((job, _)) = nearest {
+                job.claimed_by = Some(entity);
+                worker.task = job.kind.task();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{WorldExt, Join};
+
+    use super::*;
+    use crate::sim::Simulation;
+    use crate::needs::Needs;
+
+    fn diggers(sim: &Simulation, r: u32, c: u32) -> usize {
+        sim.ecs.read_storage::<Worker>().join().filter(|worker| worker.task == WorkerTask::Dig(r, c)).count()
+    }
+
+    #[test]
+    fn interrupted_worker_keeps_its_job() {
+        let mut sim = Simulation::new(2);
+        let (r, c) = {
+            let map = sim.ecs.fetch::<Map>();
+            let (sr, sc) = map.spawn_points()[0];
+            (0..map.rows()).flat_map(|r| (0..map.cols()).map(move |c| (r, c)))
+                .filter(|&(r, c)| map.at(r, c).minable
+                    && [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|(dr, dc)| map.is_on(r as i32 + dr, c as i32 + dc)
+                        && map.at((r as i32 + dr) as u32, (c as i32 + dc) as u32).walkable))
+                .min_by_key(|&pos| distance(pos, (sr, sc)))
+                .unwrap()
+        };
+        sim.ecs.write_resource::<JobBoard>().post(JobKind::Dig(r, c));
+        sim.step();
+        let claimant = sim.ecs.fetch::<JobBoard>().jobs().next().unwrap().claimed_by.unwrap();
+
+        // tired enough for a long nap
+        sim.ecs.write_storage::<Needs>().get_mut(claimant).unwrap().fatigue = 50;
+        sim.ecs.write_storage::<Worker>().get_mut(claimant).unwrap().interrupt(WorkerTask::Sleep);
+        sim.step_n(5);
+        assert_eq!(sim.ecs.fetch::<JobBoard>().jobs().next().unwrap().claimed_by, Some(claimant));
+        assert_eq!(diggers(&sim, r, c), 0);
+        assert!(sim.ecs.read_storage::<Worker>().get(claimant).unwrap().queue.front() == Some(&WorkerTask::Dig(r, c)));
+    }
+
+    // the dig is in the wall of a room the worker has no way into
+    const CUT_OFF: &str = "[meta]\nspawn = 1,1\n[legend]\n. = floor\nw = wall\n[map]\nwwwwwwwww\nw..w....w\nwwwwwwwww\n";
+
+    fn claimed_by(sim: &Simulation) -> Option<Entity> {
+        sim.ecs.fetch::<JobBoard>().jobs().next().unwrap().claimed_by
+    }
+
+    #[test]
+    fn unreachable_job_is_retried_later() {
+        let mut sim = Simulation::from_text(0, CUT_OFF);
+        sim.ecs.write_resource::<JobBoard>().post(JobKind::Dig(8, 1));
+        sim.step();
+        let worker = claimed_by(&sim).expect("nobody took the job");
+
+        for _ in 0..100 {
+            if claimed_by(&sim).is_none() {
+                break;
+            }
+            sim.step();
+        }
+        assert_eq!(claimed_by(&sim), None, "never gave up on it");
+        let released = sim.ecs.fetch::<SimClock>().now();
+
+        while claimed_by(&sim).is_none() {
+            sim.step();
+        }
+        let waited = sim.ecs.fetch::<SimClock>().now() - released;
+        assert_eq!(claimed_by(&sim), Some(worker));
+        assert!((RETRY_TICKS..RETRY_TICKS + 2 * crate::time::TICKS_PER_FRAME).contains(&waited), "taken again after {} ticks", waited);
+    }
+}
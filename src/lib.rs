@@ -0,0 +1,443 @@
+use rltk::RandomNumberGenerator;
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::{Join, Entities};
+use std::collections::{HashMap, VecDeque};
+
+pub mod map;
+
+pub mod tile;
+use tile::BASE_COST;
+use map::Map;
+
+pub mod time;
+use time::{Actor, ActionType, ActionFailure, SimClock};
+
+pub mod path;
+use path::{is_adjacent, distance};
+
+pub mod resource;
+use resource::{ResourceKind, ResourceDeposit, Inventory, Stockpile, Resources};
+
+pub mod input;
+
+pub mod sim;
+
+pub mod save;
+
+pub mod vision;
+
+pub mod camera;
+
+pub mod job;
+
+pub mod build;
+use build::{Blueprint, BuildingCatalog};
+
+pub mod combat;
+
+pub mod ai;
+
+pub mod needs;
+use needs::{Needs, Bed, MEAL};
+
+pub mod skill;
+
+pub mod hq;
+
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Transform{
+    pub r: u32,
+    pub c: u32,
+    pub ch: u16,
+    pub color: rltk::RGB
+}
+
+
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Worker{
+    pub task: WorkerTask,
+    pub queue: VecDeque<WorkerTask>,// shift queued orders, started once `task` is done
+    stale_action: bool,// whatever the actor is doing belongs to an older order
+}
+
+impl Worker{
+    pub fn new() -> Self {
+        Self { task: WorkerTask::Idle, queue: VecDeque::new(), stale_action: false }
+    }
+
+    /// Replaces the current task and anything queued after it, the action under way is dropped.
+    pub fn order(&mut self, task: WorkerTask) {
+        self.task = task;
+        self.queue.clear();
+        self.stale_action = true;
+    }
+
+    /// Runs `task` after everything else, or right away when there's nothing to do.
+    pub fn queue_order(&mut self, task: WorkerTask) {
+        match self.task {
+            WorkerTask::Idle => self.task = task,
+            _ => self.queue.push_back(task),
+        }
+    }
+
+    /// Does `task` first and then goes back to whatever it was doing, starting that over.
+    pub fn interrupt(&mut self, task: WorkerTask) {
+        if self.task != WorkerTask::Idle {
+            self.queue.push_front(self.task);
+        }
+        self.task = task;
+        self.stale_action = true;
+    }
+
+    // moves on to the next queued order, or goes idle
+    fn finish_task(&mut self) {
+        self.task = self.queue.pop_front().unwrap_or(WorkerTask::Idle);
+    }
+}
+
+impl Default for Worker{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+use specs::Entity;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WorkerTask{
+    Idle,
+    Mine(Entity),
+    MoveTo(u32, u32),
+    Dig(u32, u32),// a wall tile, worked from next to it
+    Build(Entity),// a blueprint
+    Attack(Entity),// chased down and hit until it drops
+    Eat,// at the nearest stockpile until fed
+    Sleep,// next to the nearest bed, or on the floor, until rested
+}
+
+use specs::System;
+use specs::{Read, ReadExpect, WriteExpect, ReadStorage, WriteStorage};
+pub struct WorkManager;
+
+impl<'a> System<'a> for WorkManager{
+    type SystemData = ( Entities<'a>,
+                        Read<'a, SimClock>,
+                        WriteExpect<'a, RandomNumberGenerator>,
+                        ReadExpect<'a, Map>,
+                        Read<'a, Resources>,
+                        ReadExpect<'a, BuildingCatalog>,
+                        ReadStorage<'a, Blueprint>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, ResourceDeposit>,
+                        ReadStorage<'a, Stockpile>,
+                        ReadStorage<'a, Inventory>,
+                        ReadStorage<'a, Bed>,
+                        WriteStorage<'a, Needs>,
+                        WriteStorage<'a, Worker>,
+                        WriteStorage<'a, Actor>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (entities, clock, mut rand, map, resources, catalog, blueprints, transforms, deposits, stockpiles, inventories, beds, mut needs, mut worker, mut actors) = data;
+        let now = clock.now();
+        let nearest_stockpile = |pos: (u32, u32)| (&entities, &stockpiles, &transforms).join()
+            .map(|(e, _, trans)| (e, (trans.r, trans.c)))
+            .min_by_key(|(_, sp)| distance(pos, *sp));
+        let nearest_bed = |pos: (u32, u32)| (&entities, &beds, &transforms).join()
+            .map(|(e, _, trans)| (e, (trans.r, trans.c)))
+            .min_by_key(|(_, at)| distance(pos, *at));
+
+        for (worker, act, pos, inventory, mut needs) in (&mut worker, &mut actors, &transforms, &inventories, (&mut needs).maybe()).join() {
+            let pos = (pos.r, pos.c);
+            if let Some(ActionFailure::Unreachable(..)) = act.take_failure() {
+                match (worker.task, needs.as_deref_mut()) {
+                    // no way to the bed, the floor will do
+                    (WorkerTask::Sleep, _) => {
+                        act.new_action(ActionType::Sleep(None), now);
+                    },
+                    (WorkerTask::Eat, Some(needs)) => {
+                        needs.put_off_eating(now);
+                        worker.finish_task();
+                    },
+                    _ => worker.finish_task(),
+                }
+            }
+            if let (WorkerTask::Idle, false) = (worker.task, worker.queue.is_empty()) {
+                worker.finish_task();
+            }
+            // needs come first, whatever it was doing gets picked up again afterwards. Fights don't wait.
+            if let Some(needs) = needs.as_deref() {
+                let food = resources.get(ResourceKind::Food) > 0 && nearest_stockpile(pos).is_some();
+                match worker.task {
+                    WorkerTask::Eat | WorkerTask::Sleep | WorkerTask::Attack(_) => {},
+                    _ if needs.is_hungry() && food && needs.can_eat(now) => worker.interrupt(WorkerTask::Eat),
+                    _ if needs.is_tired() => worker.interrupt(WorkerTask::Sleep),
+                    _ => {},
+                }
+            }
+            if std::mem::take(&mut worker.stale_action) {
+                act.cancel();
+            }
+            let go_to = |act: &mut Actor, to: (u32, u32), action: ActionType| if is_adjacent(pos, to) {
+                act.new_action(action, now);
+            } else {
+                act.new_action(ActionType::MoveTo(to.0, to.1), now);
+            };
+            match worker.task {
+                WorkerTask::Idle => {
+                    let (dr, dc) = match rand.range::<i32>(0, 4) {
+                        0 => (1, 0),
+                        1 => (-1, 0),
+                        2 => (0, 1),
+                        3 => (0, -1),
+                        _ => panic!("rand.range in worker move returned weird value")
+                    };
+                    if !act.is_busy() {
+                        act.new_action(ActionType::Move(dr, dc), now);
+                    }
+                },
+                WorkerTask::Mine(entity) => {
+                    let deposit = match (transforms.get(entity), deposits.contains(entity)) {
+                        (Some(trans), true) => Some((trans.r, trans.c)),
+                        _ => None,
+                    };
+                    // mine until full, haul to the nearest stockpile, repeat until the deposit runs out
+                    let hauling = inventory.is_full() || (deposit.is_none() && !inventory.is_empty());
+                    if hauling {
+                        match nearest_stockpile(pos) {
+                            Some((stockpile, sp)) => if !act.is_busy() {
+                                if is_adjacent(pos, sp) {
+                                    act.new_action(ActionType::Deliver(stockpile), now);
+                                } else {
+                                    act.new_action(ActionType::MoveTo(sp.0, sp.1), now);
+                                }
+                            },
+                            None => worker.finish_task(),
+                        }
+                    } else if let Some(target) = deposit {
+                        if !act.is_busy() {
+                            if is_adjacent(pos, target) {
+                                act.new_action(ActionType::Mine(entity), now);
+                            } else {
+                                act.new_action(ActionType::MoveTo(target.0, target.1), now);
+                            }
+                        }
+                    } else {
+                        worker.finish_task();
+                    }
+                }
+                WorkerTask::MoveTo(dr, dc) => {
+                    // still walking a route from an earlier order
+                    if act.destination().is_some_and(|to| to != (dr, dc)) {
+                        act.cancel();
+                    }
+                    // a waypoint is passed once reached, the last one is held
+                    let arrived = pos == (dr, dc) || (is_adjacent(pos, (dr, dc)) && !act.is_busy());
+                    if arrived && !worker.queue.is_empty() {
+                        worker.finish_task();
+                    } else if !act.is_busy() && pos != (dr, dc) {
+                        act.new_action(ActionType::MoveTo(dr, dc), now);
+                    }
+                },
+                WorkerTask::Dig(r, c) => {
+                    if !map.at(r, c).minable {
+                        worker.finish_task();
+                    } else if !act.is_busy() {
+                        // full hands get emptied first, without a stockpile the rubble just piles up
+                        match (inventory.is_full(), nearest_stockpile(pos)) {
+                            (true, Some((stockpile, sp))) if is_adjacent(pos, sp) => {
+                                act.new_action(ActionType::Deliver(stockpile), now);
+                            },
+                            (true, Some((_, sp))) => {
+                                act.new_action(ActionType::MoveTo(sp.0, sp.1), now);
+                            },
+                            _ if is_adjacent(pos, (r, c)) => {
+                                act.new_action(ActionType::Dig(r, c), now);
+                            },
+                            _ => {
+                                act.new_action(ActionType::MoveTo(r, c), now);
+                            },
+                        }
+                    }
+                },
+                WorkerTask::Attack(target) => {
+                    match transforms.get(target) {
+                        None => worker.finish_task(),
+                        Some(trans) => if !act.is_busy() {
+                            let at = (trans.r, trans.c);
+                            if is_adjacent(pos, at) {
+                                act.new_action(ActionType::Attack(target), now);
+                            } else {
+                                act.new_action(ActionType::MoveTo(at.0, at.1), now);
+                            }
+                        },
+                    }
+                },
+                WorkerTask::Build(site) => {
+                    let (target, missing) = match (transforms.get(site), blueprints.get(site)) {
+                        (Some(trans), Some(blueprint)) => ((trans.r, trans.c), blueprint.missing(&catalog)),
+                        _ => {
+                            worker.finish_task();
+                            continue;
+                        },
+                    };
+                    if act.is_busy() {
+                        continue;
+                    }
+                    // bring over what's in hand, fetch the rest from the stockpile, then build
+                    let in_hand = missing.iter().any(|&(kind, _)| inventory.count(kind) > 0);
+                    match (missing.first(), nearest_stockpile(pos)) {
+                        (None, _) => go_to(act, target, ActionType::Build(site)),
+                        _ if in_hand => go_to(act, target, ActionType::Supply(site)),
+                        // nothing left in store to build it with
+                        (Some(&(kind, _)), _) if resources.get(kind) == 0 => worker.finish_task(),
+                        (Some(_), Some((stockpile, sp))) if inventory.is_full() => go_to(act, sp, ActionType::Deliver(stockpile)),
+                        (Some(&(kind, amount)), Some((stockpile, sp))) => go_to(act, sp, ActionType::Withdraw(stockpile, kind, amount)),
+                        (Some(_), None) => worker.finish_task(),
+                    }
+                },
+                WorkerTask::Eat => {
+                    // one more meal would be wasted
+                    let fed = needs.as_deref().is_none_or(|needs| needs.hunger < MEAL);
+                    match nearest_stockpile(pos) {
+                        Some((stockpile, sp)) if !fed && resources.get(ResourceKind::Food) > 0 => if !act.is_busy() {
+                            go_to(act, sp, ActionType::Eat(stockpile));
+                        },
+                        _ => worker.finish_task(),
+                    }
+                },
+                WorkerTask::Sleep => {
+                    if needs.as_deref().is_none_or(|needs| needs.fatigue == 0) {
+                        worker.finish_task();
+                    } else if !act.is_busy() {
+                        match nearest_bed(pos) {
+                            Some((bed, at)) => go_to(act, at, ActionType::Sleep(Some(bed))),
+                            None => {
+                                act.new_action(ActionType::Sleep(None), now);
+                            },
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Where things can walk. `map` only has the terrain and things that never move,
+/// units and the tiles they are about to step on are tracked on the side and rebuilt every frame.
+#[derive(Serialize, Deserialize)]
+pub struct MoveMap{
+    map: Vec<Vec<bool>>,
+    cost: Vec<Vec<u32>>,// percent of BASE_COST
+    min_cost: u32,
+    rows: usize,
+    cols: usize,
+    #[serde(skip)]
+    units: HashMap<(u32, u32), Entity>,
+    #[serde(skip)]
+    reserved: HashMap<(u32, u32), Entity>,
+}
+
+impl MoveMap{
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            map: vec![vec![true ; cols]; rows],
+            cost: vec![vec![BASE_COST ; cols]; rows],
+            min_cost: BASE_COST,
+            rows,
+            cols,
+            units: HashMap::new(),
+            reserved: HashMap::new(),
+        }
+    }
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+    pub fn is_walkable(&self, r: i32, c: i32) -> bool {
+        if r < 0 || c < 0 || r as usize >= self.rows || c as usize >= self.cols {
+            return false;
+        }
+        self.map[r as usize][c as usize]
+    }
+    /// Time to step onto a tile in percent of plain floor.
+    pub fn move_cost(&self, r: i32, c: i32) -> u32 {
+        if r < 0 || c < 0 || r as usize >= self.rows || c as usize >= self.cols {
+            return BASE_COST;
+        }
+        self.cost[r as usize][c as usize]
+    }
+    /// The lowest `move_cost` anywhere on the map.
+    pub fn min_cost(&self) -> u32 {
+        self.min_cost
+    }
+    pub fn unit_at(&self, r: i32, c: i32) -> Option<Entity> {
+        if r < 0 || c < 0 {
+            return None;
+        }
+        self.units.get(&(r as u32, c as u32)).copied()
+    }
+    pub fn reserved_by(&self, r: i32, c: i32) -> Option<Entity> {
+        if r < 0 || c < 0 {
+            return None;
+        }
+        self.reserved.get(&(r as u32, c as u32)).copied()
+    }
+    /// Whether `entity` can step onto the tile right now.
+    pub fn is_free_for(&self, entity: Entity, r: i32, c: i32) -> bool {
+        self.is_walkable(r, c) && self.unit_at(r, c).is_none()
+            && self.reserved_by(r, c).is_none_or(|owner| owner == entity)
+    }
+    pub(crate) fn move_unit(&mut self, entity: Entity, from: (u32, u32), to: (u32, u32)) {
+        if self.units.get(&from) == Some(&entity) {
+            self.units.remove(&from);
+        }
+        self.units.insert(to, entity);
+    }
+    /// Claims a tile for the next step, first come first served.
+    pub(crate) fn reserve(&mut self, entity: Entity, tile: (u32, u32)) {
+        self.reserved.entry(tile).or_insert(entity);
+    }
+}
+
+pub struct MapManager;
+
+impl<'a> System<'a> for MapManager{
+    type SystemData = ( Entities<'a>,
+                        WriteExpect<'a, MoveMap>,
+                        ReadExpect<'a, Map>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, Actor>);
+
+    fn run(&mut self, data: Self::SystemData){
+        let (entities, mut mmap, map, transforms, actors) = data;
+        mmap.map = vec![vec![true; mmap.cols ]; mmap.rows];
+        mmap.cost = vec![vec![BASE_COST; mmap.cols ]; mmap.rows];
+        for r in (0..).take_while(|i| i < &map.rows()) {
+            for c in (0..).take_while(|i| i < &map.cols()) {
+                let tile = map.at(r, c);
+                mmap.map[r as usize][c as usize] = tile.walkable;//TODO do this with a map function
+                mmap.cost[r as usize][c as usize] = tile.cost;
+            }
+        }
+        mmap.min_cost = map.types().min_cost();
+
+        for (trans, _) in (&transforms, !&actors).join() {
+            mmap.map[trans.r as usize][trans.c as usize] = false;
+        }
+
+        mmap.units.clear();
+        mmap.reserved.clear();
+        for (entity, trans, actor) in (&entities, &transforms, &actors).join() {
+            let pos = (trans.r, trans.c);
+            mmap.units.insert(pos, entity);
+            if let Some(next) = actor.next_tile(pos) {
+                mmap.reserve(entity, next);
+            }
+        }
+    }
+}
@@ -1,173 +1,157 @@
 use rltk::{Rltk, GameState};
-use rltk::RandomNumberGenerator;
-use specs::{World, WorldExt, Builder};
-use specs::{Component, VecStorage};
-use specs::Join;
+use std::time::{Duration, Instant};
+use specs::{WorldExt, Join};
 
 use bracket_lib::prelude::*;
 
-mod map;
-use map::Map;
-//use map::MapGenerator;
+use rogue::{Transform, Worker, WorkerTask, MoveMap};
+use rogue::camera::Camera;
+use rogue::job::{JobBoard, JobKind};
+use rogue::build::BuildingCatalog;
+use rogue::map::Map;
+use rogue::time::Actor;
+use rogue::input::*;
+use rogue::sim::{Simulation, Options, OPTIONS_USAGE};
+use rogue::resource::{Resources, ResourceKind};
+use rogue::combat::Health;
+use rogue::needs::Needs;
+use rogue::skill::{Skills, SkillKind};
+use rogue::hq::{self, Headquarters, TRAIN_COST};
+use rogue::needs::Bed;
+use rogue::time::SimClock;
 
-mod time;
-use time::{Actor, ActionType};
-
-mod input;
-use input::*;
-
-#[derive(Component, Debug)]
-#[storage(VecStorage)]
-pub struct Transform{
-    pub r: u32,
-    pub c: u32,
-    pub ch: u16,
-    pub color: rltk::RGB
-}
-
-
-#[derive(Component)]
-#[storage(VecStorage)]
-pub struct Worker{
-    pub task: WorkerTask,
-}
-
-use specs::{Entity, Entities};
-
-pub enum WorkerTask{
-    Idle,
-    Mine(Entity),
-    MoveTo(u32, u32),
-}
-
-use specs::System;
-use specs::{Write, ReadExpect, WriteExpect, ReadStorage, WriteStorage};
-use specs::{RunNow};
-
-struct WorkManager;
-
-impl<'a> System<'a> for WorkManager{
-    type SystemData = ( ReadStorage<'a, Transform>,
-                        ReadStorage<'a, Worker>,
-                        WriteStorage<'a, Actor>);
-
-    fn run(&mut self, data: Self::SystemData){
-        let mut rand = RandomNumberGenerator::new();
-        let (transforms ,worker, mut actors) = data;
-
-        for (worker, act) in (&worker, &mut actors).join() {
-            match &worker.task {
-                WorkerTask::Idle => {
-                    let (dr, dc) = match rand.range::<i32>(0, 4) {
-                        0 => (1, 0),
-                        1 => (-1, 0),
-                        2 => (0, 1),
-                        3 => (0, -1),
-                        _ => panic!("rand.range in worker move returned weird value")
-                    };
-                    if !act.is_busy() {
-                        act.new_action(ActionType::Move(dr, dc));
-                    }
-                },
-                WorkerTask::Mine(entity) => {
-                    if let Some(trans) = transforms.get(*entity){
-                        if !act.is_busy() {
-                            act.new_action(ActionType::MoveTo(trans.r, trans.c));
-                        }
-                    }
-                }
-                WorkerTask::MoveTo(dr, dc) => {
-                    if !act.is_busy() {
-                        act.new_action(ActionType::MoveTo(*dr, *dc));
-                    }
-                },
-            }
-        }
-    }
+struct State {
+    sim: Simulation,
+    is_mining: bool,
+    build: Option<usize>,// index into the building catalog while placing blueprints
+    draw_move_map: bool,
+    inspect: bool,// panel with the stats of the selected worker
+    select_start: Option<(u32, u32)>,// world tile
+    last_recall: Option<(u8, Instant)>,
 }
 
-pub struct MoveMap{
-    map: Vec<Vec<bool>>,
-    rows: usize,
-    cols: usize,
+fn group_key(key: VirtualKeyCode) -> Option<u8> {
+    use VirtualKeyCode::*;
+    [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9].iter().position(|k| *k == key).map(|i| i as u8)
 }
 
-struct MapManager;
-
-impl<'a> System<'a> for MapManager{
-    type SystemData = ( WriteExpect<'a, MoveMap>,
-                        ReadExpect<'a, Map>,
-                        ReadStorage<'a, Transform>);
-
-    fn run(&mut self, data: Self::SystemData){
-        let (mut mmap, map, transforms) = data;
-        mmap.map = vec![vec![true; mmap.cols ]; mmap.rows];
-        for r in (0..).take_while(|i| i < &map.rows()) {
-            for c in (0..).take_while(|i| i < &map.cols()) {
-                mmap.map[r as usize][c as usize] = map.at(r, c).walkable;//TODO do this with a map function
-            }
+// The camera lives in the world so everything that turns clicks into orders can use it.
+fn attach_camera(sim: &mut Simulation) {
+    let camera = {
+        let map = sim.ecs.fetch::<Map>();
+        let mut camera = Camera::new(SCREEN_WIDTH, SCREEN_HEIGHT, map.rows(), map.cols());
+        if let Some(&(r, c)) = map.spawn_points().first() {
+            camera.center_on(r, c);
         }
-
-        for trans in (transforms).join() {
-            mmap.map[trans.r as usize][trans.c as usize] = false;
-        }
-    }
-}
-
-struct State {
-    ecs: World,
-    is_mining: bool,
-    draw_move_map: bool,
-    select_start: Option<(i32, i32)>,
+        camera
+    };
+    sim.ecs.insert(camera);
 }
 
 impl State {
-    fn run_systems(&mut self) {
-        input::run_systems(&self.ecs);
-        let mut map_manager = MapManager{};
-        let mut wm = WorkManager{};
-        let mut tm = time::TimeManager{};
-        map_manager.run_now(&self.ecs);
-        wm.run_now(&self.ecs);
-        tm.run_now(&self.ecs);
-        self.ecs.maintain();
-    }
     fn player_input(&mut self, ctx: &mut Rltk){
         let mut input = INPUT.lock();
 
         while let Some(event) = input.pop(){
             match event {
                 BEvent::KeyboardInput{key, pressed, ..} => {
+                    if let (true, Some(group)) = (pressed, group_key(key)) {
+                        self.control_group(group, ctx.control);
+                        continue;
+                    }
                     match key {
-                        VirtualKeyCode::M => if pressed {self.is_mining = !self.is_mining },
-                        VirtualKeyCode::D => if pressed {self.draw_move_map = !self.draw_move_map },
+                        VirtualKeyCode::M if pressed => {
+                            self.is_mining = !self.is_mining;
+                            self.build = None;
+                        },
+                        // cycles through the catalog, then back to no building
+                        VirtualKeyCode::B if pressed => {
+                            let count = self.sim.ecs.fetch::<BuildingCatalog>().all().len();
+                            self.build = match self.build {
+                                None if count > 0 => Some(0),
+                                Some(i) if i + 1 < count => Some(i + 1),
+                                _ => None,
+                            };
+                            self.is_mining = false;
+                        },
+                        VirtualKeyCode::D if pressed => self.draw_move_map = !self.draw_move_map,
+                        VirtualKeyCode::I if pressed => self.inspect = !self.inspect,
+                        VirtualKeyCode::T if pressed => *self.sim.ecs.write_resource::<KeyEvent>() = KeyEvent(KeyEventT::Train),
+                        VirtualKeyCode::F5 if pressed => if let Err(err) = self.sim.save(SAVE_FILE) {
+                            eprintln!("{}", err);
+                        },
+                        VirtualKeyCode::F9 if pressed => match Simulation::load(SAVE_FILE) {
+                            Ok(mut sim) => {
+                                attach_camera(&mut sim);
+                                self.sim = sim;
+                            },
+                            Err(err) => eprintln!("{}", err),
+                        },
+                        VirtualKeyCode::Left if pressed => self.sim.ecs.write_resource::<Camera>().scroll(-CAMERA_STEP, 0),
+                        VirtualKeyCode::Right if pressed => self.sim.ecs.write_resource::<Camera>().scroll(CAMERA_STEP, 0),
+                        VirtualKeyCode::Up if pressed => self.sim.ecs.write_resource::<Camera>().scroll(0, -CAMERA_STEP),
+                        VirtualKeyCode::Down if pressed => self.sim.ecs.write_resource::<Camera>().scroll(0, CAMERA_STEP),
                         _ => {},
                     }
                 },
                 BEvent::MouseClick{button: 1, pressed: true} => {
                     let (mouse_r, mouse_c) = input.mouse_tile_pos(0);
-                    let (r, c): (u32, u32) = (mouse_r.try_into().unwrap(), mouse_c.try_into().unwrap());
-                    *self.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::MoveTo(r, c));
-                    for (entity, trans) in (&self.ecs.entities(),&self.ecs.read_storage::<Transform>()).join() {
-                        if trans.r == r && trans.c == c {
-                            *self.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::Activate(entity));
+                    let (r, c) = match self.sim.ecs.fetch::<Camera>().to_world(mouse_r, mouse_c) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
+                    // walls that were seen once can be dug, other tiles are just a place to go
+                    let diggable = {
+                        let map = self.sim.ecs.fetch::<Map>();
+                        map.is_revealed(r, c) && map.at(r, c).minable
+                    };
+                    let order = if diggable { MouseEventT::Dig(r, c) } else { MouseEventT::MoveTo(r, c) };
+                    *self.sim.ecs.write_resource::<MouseEvent>() = MouseEvent(order);
+                    *self.sim.ecs.write_resource::<QueueOrders>() = QueueOrders(ctx.shift);
+                    // only things in sight can be targeted
+                    let visible = self.sim.ecs.fetch::<Map>().is_visible(r, c);
+                    for (entity, trans) in (&self.sim.ecs.entities(),&self.sim.ecs.read_storage::<Transform>()).join() {
+                        if visible && trans.r == r && trans.c == c {
+                            *self.sim.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::Activate(entity));
                         }
                     }
                 },
-                BEvent::MouseClick{button: 0, ..} => {
-                    self.select_start = match self.select_start {
-                        None => Some(input.mouse_tile_pos(0)),
-                        Some((select_r, select_c)) => {
-                            use std::cmp::min;
-                            let (mouse_r, mouse_c) = input.mouse_tile_pos(0);
-                            let (box_r, box_c) = (min(select_r, mouse_r) ,min(select_c, mouse_c));
-                            let (box_w, box_h) = ((select_r - mouse_r).abs(), (select_c - mouse_c).abs());
-                            let (r, c, w, h): (u32, u32, u32, u32) = (box_r.try_into().unwrap(), box_c.try_into().unwrap(), box_w.try_into().unwrap(), box_h.try_into().unwrap());
-                            *self.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::BoxSelect(r, c, w, h));
-                            None
-                        },
+                BEvent::MouseClick{button: 0, pressed: true} if ctx.control => {
+                    let (mouse_r, mouse_c) = input.mouse_tile_pos(0);
+                    let (r, c) = match self.sim.ecs.fetch::<Camera>().to_world(mouse_r, mouse_c) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
+                    for (entity, trans, _) in (&self.sim.ecs.entities(), &self.sim.ecs.read_storage::<Transform>(), &self.sim.ecs.read_storage::<Selectable>()).join() {
+                        if trans.r == r && trans.c == c {
+                            *self.sim.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::ToggleSelect(entity));
+                        }
                     }
                 },
+                BEvent::MouseClick{button: 0, pressed: true} => {
+                    let (mouse_r, mouse_c) = input.mouse_tile_pos(0);
+                    self.select_start = self.sim.ecs.fetch::<Camera>().to_world(mouse_r, mouse_c);
+                },
+                BEvent::MouseClick{button: 0, pressed: false} => {
+                    // a release without a press started on the map, like after a ctrl-click, does nothing
+                    let (start_r, start_c) = match self.select_start.take() {
+                        Some(start) => start,
+                        None => continue,
+                    };
+                    let (mouse_r, mouse_c) = input.mouse_tile_pos(0);
+                    let (r, c) = self.sim.ecs.fetch::<Camera>().to_world_clamped(mouse_r, mouse_c);
+                    let event = match (self.build, (r, c) == (start_r, start_c), ctx.shift) {
+                        // in dig or build mode the box marks tiles to dig out or build on, shift takes the marks off again
+                        _ if self.is_mining && ctx.shift => MouseEventT::CancelDig(start_r, start_c, r, c),
+                        _ if self.is_mining => MouseEventT::DesignateDig(start_r, start_c, r, c),
+                        (Some(_), _, true) => MouseEventT::CancelBlueprint(start_r, start_c, r, c),
+                        (Some(building), _, false) => MouseEventT::PlaceBlueprint(building, start_r, start_c, r, c),
+                        (None, true, false) => MouseEventT::ClickSelect(r, c),
+                        (None, _, true) => MouseEventT::AddBoxSelect(start_r, start_c, r, c),
+                        (None, false, false) => MouseEventT::BoxSelect(start_r, start_c, r, c),
+                    };
+                    *self.sim.ecs.write_resource::<MouseEvent>() = MouseEvent(event);
+                },
                 BEvent::CloseRequested =>{
                     ctx.quitting = true;
                 },
@@ -175,129 +159,278 @@ impl State {
             }
         }
     }
+
+    // ctrl+N stores the selection, N recalls it, N twice in a row also jumps to the group
+    fn control_group(&mut self, group: u8, control: bool){
+        if control {
+            *self.sim.ecs.write_resource::<KeyEvent>() = KeyEvent(KeyEventT::AssignGroup(group));
+            return;
+        }
+        *self.sim.ecs.write_resource::<KeyEvent>() = KeyEvent(KeyEventT::RecallGroup(group));
+        let now = Instant::now();
+        if let Some((last, at)) = self.last_recall {
+            if last == group && now.duration_since(at) <= DOUBLE_TAP {
+                let (transforms, selectables) = (self.sim.ecs.read_storage::<Transform>(), self.sim.ecs.read_storage::<Selectable>());
+                let members: Vec<(u32, u32)> = (&transforms, &selectables).join()
+                    .filter(|(_, select)| select.in_group(group))
+                    .map(|(trans, _)| (trans.r, trans.c))
+                    .collect();
+                if !members.is_empty() {
+                    let count = members.len() as u32;
+                    let (sum_r, sum_c) = members.iter().fold((0, 0), |(sr, sc), (r, c)| (sr + r, sc + c));
+                    self.sim.ecs.write_resource::<Camera>().center_on(sum_r / count, sum_c / count);
+                }
+            }
+        }
+        self.last_recall = Some((group, now));
+    }
+
+    // scrolls while the mouse rests on the border of the window
+    fn edge_scroll(&mut self, ctx: &Rltk){
+        let (mouse_r, mouse_c) = ctx.mouse_pos();
+        let dr = if mouse_r <= 0 { -1 } else if mouse_r >= SCREEN_WIDTH - 1 { 1 } else { 0 };
+        let dc = if mouse_c <= 0 { -1 } else if mouse_c >= SCREEN_HEIGHT - 1 { 1 } else { 0 };
+        if (dr, dc) != (0, 0) {
+            self.sim.ecs.write_resource::<Camera>().scroll(dr, dc);
+        }
+    }
 }
 
 impl GameState for State {
     fn tick(&mut self, ctx : &mut Rltk) {
         ctx.cls();
         self.player_input(ctx);
-        self.run_systems();
+        self.edge_scroll(ctx);
+        self.sim.step();
 
         //let rand = RandomNumberGenerator::new();
-        let map = self.ecs.fetch::<Map>();
-        let mmap = self.ecs.fetch::<MoveMap>();
+        let map = self.sim.ecs.fetch::<Map>();
+        let mmap = self.sim.ecs.fetch::<MoveMap>();
+        let camera = self.sim.ecs.fetch::<Camera>();
+        let jobs = self.sim.ecs.fetch::<JobBoard>();
 
         if !self.draw_move_map{
-            for r in (0..).take_while(|i| i < &map.rows()) {
-                for c in (0..).take_while(|i| i < &map.cols()) {
+            for sr in 0..camera.width {
+                for sc in 0..camera.height {
+                    let (r, c) = match camera.to_world(sr, sc) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
                     let tile = &map.at(r,c);
-                    ctx.set(r,c,tile.fg, tile.bg, tile.ch);
+                    let bg = if jobs.is_posted(JobKind::Dig(r, c)) { rltk::RGB::named(JOB_MARK) } else { tile.bg };
+                    if map.is_visible(r, c) {
+                        ctx.set(sr,sc,tile.fg, bg, tile.ch);
+                    } else if map.is_revealed(r, c) {
+                        ctx.set(sr,sc,tile.fg.to_greyscale() * FOG_DIM, bg.to_greyscale() * FOG_DIM, tile.ch);
+                    }
                 }
             }
         }
 
+        let resources = self.sim.ecs.fetch::<Resources>();
+        ctx.print_color(0, 0, rltk::RGB::named(rltk::GOLD), rltk::RGB::named(rltk::BLACK),
+            format!("Gold: {} Stone: {} Food: {}", resources.get(ResourceKind::Gold), resources.get(ResourceKind::Stone), resources.get(ResourceKind::Food)));
+        let population = self.sim.ecs.read_storage::<Worker>().join().count();
+        let room = hq::housing(self.sim.ecs.read_storage::<Headquarters>().join().count(), self.sim.ecs.read_storage::<Bed>().join().count());
+        ctx.print_color(0, 1, rltk::RGB::named(rltk::GOLD), rltk::RGB::named(rltk::BLACK), format!("Workers: {}/{}", population, room));
+
         if self.is_mining {
-            ctx.print_color_centered_at(SCREEN_WIDTH /2, 0,  rltk::RGB::named(rltk::RED), rltk::RGB::named(rltk::BLACK)," * Mining * ");
+            ctx.print_color_centered_at(SCREEN_WIDTH /2, 0,  rltk::RGB::named(rltk::RED), rltk::RGB::named(rltk::BLACK)," * Dig: drag to mark, shift to clear * ");
+        }
+        if let Some(building) = self.build.and_then(|i| self.sim.ecs.fetch::<BuildingCatalog>().all().get(i).cloned()) {
+            let cost: Vec<String> = building.cost.iter().map(|(kind, amount)| format!("{} {}", kind.name(), amount)).collect();
+            let cost = if cost.is_empty() { "free".to_string() } else { cost.join(", ") };
+            ctx.print_color_centered_at(SCREEN_WIDTH /2, 0,  rltk::RGB::named(rltk::CYAN), rltk::RGB::named(rltk::BLACK),
+                format!(" * Build {} ({}), B for next * ", building.name, cost));
         }
         if let Some((select_r, select_c)) = self.select_start {
             use std::cmp::min;
+            // the start may have scrolled off screen, pin it to the border
+            let select_r = (select_r as i32 - camera.r).clamp(0, camera.width - 1);
+            let select_c = (select_c as i32 - camera.c).clamp(0, camera.height - 1);
             let (mouse_r, mouse_c) = ctx.mouse_pos();
             let (box_r, box_c) = (min(select_r, mouse_r) ,min(select_c, mouse_c));
             let (box_w, box_h) = ((select_r - mouse_r).abs(), (select_c - mouse_c).abs());
-            ctx.draw_hollow_box(box_r, box_c, box_w, box_h, rltk::RGB::named(rltk::YELLOW), rltk::RGB::named(rltk::GRAY));
+            if (box_w, box_h) != (0, 0) {
+                ctx.draw_hollow_box(box_r, box_c, box_w, box_h, rltk::RGB::named(rltk::YELLOW), rltk::RGB::named(rltk::GRAY));
+            }
         }
 
+        let (tran_storage, sel_storage, actors) = (self.sim.ecs.read_storage::<Transform>(), self.sim.ecs.read_storage::<Selectable>(), self.sim.ecs.read_storage::<Actor>());
+
+        // order queue of the selected workers, drawn under the entities
+        let target = |task: &WorkerTask| match *task {
+            WorkerTask::Idle | WorkerTask::Eat | WorkerTask::Sleep => None,
+            WorkerTask::Mine(entity) | WorkerTask::Build(entity) | WorkerTask::Attack(entity) => tran_storage.get(entity).map(|trans| (trans.r, trans.c)),
+            WorkerTask::MoveTo(r, c) | WorkerTask::Dig(r, c) => Some((r, c)),
+        };
+        for (transform, selectable, worker) in (&tran_storage, &sel_storage, &self.sim.ecs.read_storage::<Worker>()).join(){
+            if !selectable.selected {
+                continue;
+            }
+            let mut from = (transform.r, transform.c);
+            for to in std::iter::once(&worker.task).chain(worker.queue.iter()).filter_map(target) {
+                let line = line2d(LineAlg::Bresenham, Point::new(from.0, from.1), Point::new(to.0, to.1));
+                for point in line {
+                    if let Some((sr, sc)) = camera.to_screen(point.x as u32, point.y as u32) {
+                        ctx.set(sr, sc, rltk::RGB::named(rltk::CYAN), rltk::RGB::named(rltk::BLACK), rltk::to_cp437('·'));
+                    }
+                }
+                from = to;
+            }
+        }
 
-        let (tran_storage, sel_storage) = (self.ecs.read_storage::<Transform>(), self.ecs.read_storage::<Selectable>());
-        for (transform, selectable) in (&tran_storage, (&sel_storage).maybe()).join(){
+        for (entity, transform, selectable, actor) in (&self.sim.ecs.entities(), &tran_storage, (&sel_storage).maybe(), (&actors).maybe()).join(){
             let (r, c) =  (transform.r , transform.c);
+            let (sr, sc) = match camera.to_screen(r, c) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            if !map.is_visible(r, c) {
+                // things that never move stay drawn on explored tiles
+                if actor.is_none() && map.is_revealed(r, c) {
+                    let tile = map.at(r, c);
+                    ctx.set(sr, sc, transform.color.to_greyscale() * FOG_DIM, tile.bg.to_greyscale() * FOG_DIM, transform.ch);
+                }
+                continue;
+            }
             let mut bg_color = map.at(r, c).bg;
+            if jobs.is_posted(JobKind::Mine(entity)) {
+                bg_color = rltk::RGB::named(JOB_MARK);
+            }
             if let Some(select) = selectable {
                 if select.selected {
                     bg_color = rltk::RGB::named(rltk::YELLOW);
                 }
             }
-            ctx.set(r, c, transform.color, bg_color, transform.ch);
+            ctx.set(sr, sc, transform.color, bg_color, transform.ch);
         }
 
         if self.draw_move_map{
-            for r in (0..).take_while(|i| i < &mmap.rows) {
-                for c in (0..).take_while(|i| i < &mmap.cols) {
-                    let tile = &mmap.map[r][c];
-                    ctx.set(r,c,rltk::RGB::named(rltk::YELLOW), rltk::RGB::named(rltk::BLACK), if *tile { ' ' as u16 } else { '#' as u16 } );
+            for sr in 0..camera.width {
+                for sc in 0..camera.height {
+                    let (r, c) = match camera.to_world(sr, sc) {
+                        Some(tile) => tile,
+                        None => continue,
+                    };
+                    let (r, c) = (r as i32, c as i32);
+                    // units and the tiles they claimed for their next step
+                    let ch = if mmap.unit_at(r, c).is_some() { 'o' }
+                        else if mmap.reserved_by(r, c).is_some() { 'x' }
+                        else if mmap.is_walkable(r, c) { ' ' } else { '#' };
+                    ctx.set(sr,sc,rltk::RGB::named(rltk::YELLOW), rltk::RGB::named(rltk::BLACK), ch as u16);
                 }
             }
         }
 
+        if self.inspect {
+            self.draw_inspect(ctx);
+        }
     }
 }
-const SCREEN_WIDTH: i32 = 80;
-const _SCREEN_HEIGHT: i32 = 50;
 
-fn create_worker(ecs: &mut World, r_start: u32, c_start: u32) {
-    ecs.create_entity().with(Transform {
-                    r: r_start,
-                    c: c_start,
-                    ch: '@' as u16,
-                    color: rltk::RGB::named(rltk::RED)
-                }).with(
-                    Worker {
-                        task: WorkerTask::Idle
-                    },
-                ).with(
-                    Selectable{
-                        selected: false,
-                    }
-                ).with(
-                    Actor::new(2)
-                ).build();
+impl State {
+    // health, needs and skills of the first selected worker, in the bottom left corner.
+    // Headquarters show their training instead, when no worker is selected.
+    fn draw_inspect(&self, ctx: &mut Rltk){
+        let ecs = &self.sim.ecs;
+        let (selectables, workers, healths, needs, skills) = (ecs.read_storage::<Selectable>(), ecs.read_storage::<Worker>(),
+            ecs.read_storage::<Health>(), ecs.read_storage::<Needs>(), ecs.read_storage::<Skills>());
+        let headquarters = ecs.read_storage::<Headquarters>();
+        let selected = (&ecs.entities(), &selectables).join()
+            .filter(|(entity, select)| select.selected && (workers.contains(*entity) || headquarters.contains(*entity)))
+            .min_by_key(|(entity, _)| !workers.contains(*entity))
+            .map(|(entity, _)| entity);
+        let entity = match selected {
+            Some(entity) => entity,
+            None => return,
+        };
+
+        let mut lines = vec![];
+        if let Some(hq) = headquarters.get(entity) {
+            let cost: Vec<String> = TRAIN_COST.iter().map(|(kind, amount)| format!("{} {}", kind.name(), amount)).collect();
+            lines.push(format!("Headquarters {}", entity.id()));
+            lines.push(match hq.progress(ecs.fetch::<SimClock>().now()) {
+                Some(percent) => format!("training {}%, {} queued", percent, hq.queued),
+                None if hq.queued > 0 => format!("waiting, {} queued", hq.queued),
+                None => "not training".to_string(),
+            });
+            lines.push(match hq.rally {
+                Some((r, c)) => format!("rally at {},{}", r, c),
+                None => "no rally point".to_string(),
+            });
+            lines.push(format!("T to train ({})", cost.join(", ")));
+        } else {
+            lines.push(format!("Worker {}", entity.id()));
+        }
+        if let Some(health) = healths.get(entity) {
+            lines.push(format!("health  {}/{}", health.hp, health.max_hp));
+        }
+        if let Some(needs) = needs.get(entity) {
+            lines.push(format!("hunger  {}  fatigue {}", needs.hunger, needs.fatigue));
+        }
+        if let Some(skills) = skills.get(entity) {
+            for kind in SkillKind::ALL {
+                let next = match skills.xp_to_next(kind) {
+                    Some(xp) => format!("{} xp to next", xp),
+                    None => "max".to_string(),
+                };
+                lines.push(format!("{:<9}{:>2}  {}", kind.name(), skills.level(kind), next));
+            }
+        }
+
+        let (width, height) = (INSPECT_WIDTH, lines.len() as i32 + 1);
+        let (x, y) = (0, SCREEN_HEIGHT - height - 1);
+        let (fg, bg) = (rltk::RGB::named(rltk::WHITE), rltk::RGB::named(rltk::BLACK));
+        ctx.draw_box(x, y, width, height, fg, bg);
+        for (i, line) in lines.iter().enumerate() {
+            ctx.print_color(x + 1, y + 1 + i as i32, fg, bg, line);
+        }
+    }
 }
 
+const SCREEN_WIDTH: i32 = 80;
+const SCREEN_HEIGHT: i32 = 50;
+const INSPECT_WIDTH: i32 = 30;
+const SAVE_FILE: &str = "savegame.json";
+const FOG_DIM: f32 = 0.5;
+const JOB_MARK: (u8, u8, u8) = rltk::DARK_ORANGE;
+const CAMERA_STEP: i32 = 4;
+const DOUBLE_TAP: Duration = Duration::from_millis(400);
+
 fn main() -> rltk::BError{
     use rltk::RltkBuilder;
+    let mut sim = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => match options.start() {
+            Ok(sim) => sim,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            },
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("usage: rogue {}", OPTIONS_USAGE);
+            std::process::exit(1);
+        },
+    };
+
+    attach_camera(&mut sim);
+
     INPUT.lock().activate_event_queue();
     let context = RltkBuilder::simple80x50()
         .with_title("Rougelike Tutorial")
         .with_fps_cap(30.0)
         .build()?;
 
-    let (size_x, size_y) = context.get_char_size();
-
-    let mut world = World::new();
-    world.register::<Actor>();
-    world.register::<Transform>();
-    world.register::<Worker>();
-    world.register::<Selectable>();
-
-    create_worker(&mut world, size_x / 2 - 3, size_y / 2);
-    create_worker(&mut world, size_x / 2 + 3, size_y / 2);
-    create_worker(&mut world, size_x / 2, size_y / 2);
-
-
-    //let mut map_gen = MapGenerator::new(size_x, size_y);
-    //map_gen.gold_count = 64;
-    //map_gen.gold_size = 6;
-    let map = Map::basic_80x50(&mut world);
-    let rows = map.rows() as usize;
-    let cols = map.cols() as usize;
-    //let mut map = Map::new(size_x, size_y);
-
-    // RESOURCES
-    world.insert(MouseEvent(MouseEventT::Empty));
-    world.insert(IsSomeSelected(false));
-    world.insert(map);
-
-    world.insert(MoveMap{
-        map: vec![vec![true ; cols]; rows],
-        rows: rows,
-        cols: cols,
-    });
-
     let gs = State{
-        ecs: world,
+        sim,
         is_mining: false,
+        build: None,
         draw_move_map: false,
+        inspect: false,
         select_start: None,
+        last_recall: None,
     };
 
 
@@ -1,41 +1,28 @@
-use specs::{World, WorldExt, Builder};
-use super::Transform;
+use std::fmt;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use specs::World;
+use super::resource::ResourceKind;
+use super::sim::{create_deposit, create_stockpile, create_monster, create_headquarters};
 //use specs::{Component, VecStorage};
 
-use rltk::RGB;
 use rltk::RandomNumberGenerator;
+use super::tile::{TileId, TileType, TileTypes};
 
-#[derive(Clone, Copy)]
-pub struct Tile {
-    pub fg: RGB,
-    pub bg: RGB,
-    pub ch: u16,
-    pub walkable: bool,
-}
-
-pub fn blank_tile() -> Tile{
-    Tile {
-        fg: RGB::named(rltk::WHITE),
-        bg: RGB::named(rltk::GREY),
-        ch: ' ' as u16,
-        walkable: true
-    }
-}
-
-pub fn default_wall() -> Tile{
-    Tile {
-        fg: RGB::named(rltk::WHITE),
-        bg: RGB::named(rltk::GREY),
-        ch: '#' as u16,
-        walkable: false
-    }
-}
+pub const MAX_MAP_SIZE: usize = 512;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Map {
-    vec: Vec<Vec<Tile>>,
+    vec: Vec<Vec<TileId>>,
+    types: TileTypes,
     rows: usize,
     cols: usize,
+    name: String,
+    spawns: Vec<(u32, u32)>,
+    revealed: Vec<Vec<bool>>,// seen at some point
+    visible: Vec<Vec<bool>>,// seen by some worker right now
+    revision: u32,// bumped on every terrain change
 }
 
 /*
@@ -44,123 +31,433 @@ pub struct Map {
 pub struct MapObject;
 */
 
+#[derive(Debug)]
+pub enum MapLoadError {
+    Io(std::io::Error),
+    MissingSection(&'static str),
+    MissingTileType(&'static str),// floor and wall have to be there
+    BadLine { line: usize, reason: String },
+    UnknownGlyph { glyph: char, line: usize, column: usize },
+    RaggedRow { line: usize, expected: usize, found: usize },
+    BadDimensions { rows: usize, cols: usize },
+    BadSpawn { r: u32, c: u32 },
+}
+
+impl fmt::Display for MapLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapLoadError::Io(err) => write!(f, "could not read map: {}", err),
+            MapLoadError::MissingSection(section) => write!(f, "map has no [{}] section", section),
+            MapLoadError::MissingTileType(name) => write!(f, "tile types have no [{}] type", name),
+            MapLoadError::BadLine { line, reason } => write!(f, "line {}: {}", line, reason),
+            MapLoadError::UnknownGlyph { glyph, line, column } =>
+                write!(f, "line {}, column {}: glyph '{}' is not in the legend", line, column, glyph),
+            MapLoadError::RaggedRow { line, expected, found } =>
+                write!(f, "line {}: map row is {} tiles long, expected {}", line, found, expected),
+            MapLoadError::BadDimensions { rows, cols } =>
+                write!(f, "map is {}x{}, sizes must be between 1 and {}", rows, cols, MAX_MAP_SIZE),
+            MapLoadError::BadSpawn { r, c } => write!(f, "spawn point {},{} is not on a walkable tile", r, c),
+        }
+    }
+}
+
+impl std::error::Error for MapLoadError {}
+
+impl From<std::io::Error> for MapLoadError {
+    fn from(err: std::io::Error) -> Self {
+        MapLoadError::Io(err)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Glyph {
+    Tile(TileId),
+    Deposit(ResourceKind, u32),
+    Stockpile,
+    Monster,
+    Headquarters,
+}
+
+fn parse_glyph(value: &str, types: &TileTypes) -> Result<Glyph, String> {
+    if let Some(id) = types.id(value) {
+        return Ok(Glyph::Tile(id));
+    }
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["stockpile"] => Ok(Glyph::Stockpile),
+        ["monster"] => Ok(Glyph::Monster),
+        ["headquarters"] => Ok(Glyph::Headquarters),
+        ["deposit", kind, amount] => {
+            let kind = ResourceKind::from_name(kind).ok_or(format!("unknown resource '{}'", kind))?;
+            let amount = amount.parse().map_err(|_| format!("bad deposit amount '{}'", amount))?;
+            Ok(Glyph::Deposit(kind, amount))
+        },
+        _ => Err(format!("unknown legend entry '{}', not a tile type or prefab", value)),
+    }
+}
+
+fn parse_point(value: &str) -> Option<(u32, u32)> {
+    let (r, c) = value.split_once(',')?;
+    Some((r.trim().parse().ok()?, c.trim().parse().ok()?))
+}
+
 impl Map{
     pub fn basic_80x50(world: &mut World) -> Self {
-        let map =
-            vec!["................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                ".....................w..........................................................",
-                "....................ww..........................................................",
-                "...................ww....M......................................................",
-                "..................ww....MM......................................................",
-                "..................w.............................................................",
-                ".................ww..M..........................................................",
-                "................ww..MM..........................................................",
-                "................w...............................................................",
-                "................w...............................................................",//center
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................",
-                "................................................................................"];
-        let mut vec = vec![vec![blank_tile() ; 50]; 80];
-        for (r, row) in map.iter().enumerate(){
-            for (c, ch) in row.chars().enumerate() {
-                vec[c][r] = match ch {
-                    '.' => blank_tile(),
-                    'w' => default_wall(),
-                    'M' => {
-                        world.create_entity().with(Transform {
-                            r: r.try_into().unwrap(),
-                            c: c.try_into().unwrap(),
-                            ch: ch as u16,
-                            color: rltk::RGB::named(rltk::BLUE)
-                        }).build();
-                        blank_tile()
-                    }
-                    _ => blank_tile(),
+        Self::parse(include_str!("../maps/basic_80x50.txt"), world).expect("built in map is broken")
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, world: &mut World) -> Result<Map, MapLoadError> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text, world)
+    }
+
+    /// Reads a map made of `[meta]`, `[legend]` and `[map]` sections, see `maps/basic_80x50.txt`.
+    /// Prefab entities are only created once the whole file checks out.
+    pub fn parse(text: &str, world: &mut World) -> Result<Map, MapLoadError> {
+        let types = TileTypes::builtin();
+        let mut section = "";
+        let mut name = String::new();
+        let mut spawns = vec![];
+        let mut legend: Vec<(char, Glyph)> = vec![];
+        let mut rows: Vec<(usize, &str)> = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let trimmed = line.trim();
+            if section == "map" {
+                rows.push((line_no, line.trim_end()));
+                continue;
+            }
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                section = match &trimmed[1..trimmed.len() - 1] {
+                    "meta" => "meta",
+                    "legend" => "legend",
+                    "map" => "map",
+                    other => return Err(MapLoadError::BadLine { line: line_no, reason: format!("unknown section [{}]", other) }),
+                };
+                continue;
+            }
+            let bad_line = |reason: String| MapLoadError::BadLine { line: line_no, reason };
+            let (key, value) = trimmed.split_once('=').ok_or_else(|| bad_line("expected 'key = value'".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            match section {
+                "meta" => match key {
+                    "name" => name = value.to_string(),
+                    "spawn" => spawns.push(parse_point(value).ok_or_else(|| bad_line(format!("bad spawn point '{}'", value)))?),
+                    _ => return Err(bad_line(format!("unknown meta key '{}'", key))),
+                },
+                "legend" => {
+                    let mut chars = key.chars();
+                    let glyph = match (chars.next(), chars.next()) {
+                        (Some(glyph), None) => glyph,
+                        _ => return Err(bad_line(format!("legend key '{}' must be a single character", key))),
+                    };
+                    legend.push((glyph, parse_glyph(value, &types).map_err(bad_line)?));
+                },
+                _ => return Err(bad_line("line outside of any section".to_string())),
+            }
+        }
+
+        if section != "map" {
+            return Err(MapLoadError::MissingSection("map"));
+        }
+        while matches!(rows.last(), Some((_, row)) if row.is_empty()) {
+            rows.pop();
+        }
+
+        // map lines run along r, so the line length is the number of rows
+        let n_rows = rows.first().map(|(_, row)| row.chars().count()).unwrap_or(0);
+        let n_cols = rows.len();
+        if !(1..=MAX_MAP_SIZE).contains(&n_rows) || !(1..=MAX_MAP_SIZE).contains(&n_cols) {
+            return Err(MapLoadError::BadDimensions { rows: n_rows, cols: n_cols });
+        }
+
+        let floor = types.floor();
+        let mut glyphs = vec![vec![Glyph::Tile(floor); n_cols]; n_rows];
+        for (c, (line_no, row)) in rows.iter().enumerate() {
+            let found = row.chars().count();
+            if found != n_rows {
+                return Err(MapLoadError::RaggedRow { line: *line_no, expected: n_rows, found });
+            }
+            for (r, ch) in row.chars().enumerate() {
+                glyphs[r][c] = match legend.iter().find(|(glyph, _)| *glyph == ch) {
+                    Some((_, glyph)) => *glyph,
+                    None => return Err(MapLoadError::UnknownGlyph { glyph: ch, line: *line_no, column: r + 1 }),
+                };
+            }
+        }
+
+        let mut map = Map::with_types(n_rows, n_cols, types);
+        map.name = name;
+        for &(r, c) in spawns.iter() {
+            let open = (r as usize) < n_rows && (c as usize) < n_cols
+                && matches!(glyphs[r as usize][c as usize], Glyph::Tile(tile) if map.types.get(tile).walkable);
+            if !open {
+                return Err(MapLoadError::BadSpawn { r, c });
+            }
+        }
+        map.spawns = spawns;
+
+        for (r, column) in glyphs.into_iter().enumerate() {
+            for (c, glyph) in column.into_iter().enumerate() {
+                let (er, ec) = (r as u32, c as u32);
+                match glyph {
+                    Glyph::Tile(tile) => map.set(r, c, tile),
+                    Glyph::Deposit(kind, amount) => {
+                        map.set(r, c, floor);
+                        create_deposit(world, er, ec, kind, amount);
+                    },
+                    Glyph::Stockpile => {
+                        map.set(r, c, floor);
+                        create_stockpile(world, er, ec);
+                    },
+                    Glyph::Monster => {
+                        map.set(r, c, floor);
+                        create_monster(world, er, ec);
+                    },
+                    Glyph::Headquarters => {
+                        map.set(r, c, floor);
+                        create_headquarters(world, er, ec);
+                    },
                 }
             }
         }
-        Self{ vec, rows: 80, cols: 50 }
+        Ok(map)
     }
 
+    /// A map of solid wall using the built in tile types.
     pub fn new(rows: usize, cols: usize) -> Self {
+        Self::with_types(rows, cols, TileTypes::builtin())
+    }
+
+    pub fn with_types(rows: usize, cols: usize, types: TileTypes) -> Self {
+        let vec = vec![vec![types.wall() ; cols]; rows];
+        let revealed = vec![vec![false; cols]; rows];
+        let visible = revealed.clone();
+        Self { vec, types, rows, cols, name: String::new(), spawns: vec![], revealed, visible, revision: 0 }
+    }
 
-        let vec = vec![vec![default_wall() ; cols]; rows];
-        Self { vec ,rows, cols }
+    pub fn types(&self) -> &TileTypes {
+        &self.types
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn spawn_points(&self) -> &[(u32, u32)] {
+        &self.spawns
+    }
     pub fn rows(&self) -> u32 {
         self.rows.try_into().unwrap()
     }
     pub fn cols(&self) -> u32 {
         self.cols.try_into().unwrap()
     }
-    pub fn at(&self, r: u32, c: u32) -> &Tile{
+    pub fn at(&self, r: u32, c: u32) -> &TileType{
+        self.types.get(self.tile_id(r, c))
+    }
+    pub fn tile_id(&self, r: u32, c: u32) -> TileId{
         let (y, x) : (usize, usize) = (r.try_into().unwrap(), c.try_into().unwrap());
-        &self.vec[y][x]
+        self.vec[y][x]
     }
-    pub fn set(&mut self, y: usize, x: usize, tile: Tile){
+    pub fn set(&mut self, y: usize, x: usize, tile: TileId){
         self.vec[y][x] = tile;
+        self.revision = self.revision.wrapping_add(1);
+    }
+    /// Changes whenever some tile does, so whatever depends on the terrain knows to look again.
+    pub fn revision(&self) -> u32{
+        self.revision
+    }
+    fn walkable_at(&self, y: usize, x: usize) -> bool{
+        self.types.get(self.vec[y][x]).walkable
     }
     pub fn is_on(&self, y: i32, x: i32) -> bool{
         if y < 0 || x < 0  { return false; }
         if y >= self.rows as i32 || x >= self.cols as i32 { return false; }
         true
     }
+    /// Walls block sight, off the map counts as wall.
+    pub fn is_opaque(&self, y: i32, x: i32) -> bool{
+        !self.is_on(y, x) || self.types.get(self.vec[y as usize][x as usize]).opaque
+    }
+    pub fn is_revealed(&self, r: u32, c: u32) -> bool{
+        self.revealed[r as usize][c as usize]
+    }
+    pub fn is_visible(&self, r: u32, c: u32) -> bool{
+        self.visible[r as usize][c as usize]
+    }
+    pub fn clear_visible(&mut self){
+        for column in self.visible.iter_mut(){
+            column.iter_mut().for_each(|v| *v = false);
+        }
+    }
+    /// Turns a minable tile into floor and returns what it leaves behind.
+    pub fn dig_out(&mut self, r: u32, c: u32) -> Option<(ResourceKind, u32)>{
+        let tile = self.at(r, c);
+        if !tile.minable {
+            return None;
+        }
+        let drops = tile.drops;
+        self.set(r as usize, c as usize, self.types.floor());
+        drops
+    }
+    /// Marks a tile as seen right now, which also reveals it for good.
+    pub fn see(&mut self, r: u32, c: u32){
+        self.visible[r as usize][c as usize] = true;
+        self.revealed[r as usize][c as usize] = true;
+    }
 }
 
 fn clear_room(map: Map, y: u32, x: u32, rows: u32, cols: u32) -> Map{
     let mut ret_map = map.clone();
+    let floor = ret_map.types.floor();
     for xi in x..(x+cols){
         for yi in y..(y+rows){
-            ret_map.set(yi.try_into().unwrap(), xi.try_into().unwrap(), blank_tile());
+            ret_map.set(yi.try_into().unwrap(), xi.try_into().unwrap(), floor);
         }
     }
     ret_map
 }
 
+/// Floor tiles that can be walked to from `start`. Uses 4-way steps, units can't squeeze
+/// through diagonal-only gaps.
+fn flood(map: &Map, start: (usize, usize)) -> Vec<Vec<bool>>{
+    let mut reached = vec![vec![false; map.cols]; map.rows];
+    let mut stack = vec![start];
+    reached[start.0][start.1] = true;
+    while let Some((y, x)) = stack.pop(){
+        for (dy, dx) in [(1, 0), (-1, 0), (0, 1), (0, -1)]{
+            let (ny, nx) = (y as i32 + dy, x as i32 + dx);
+            if map.is_on(ny, nx) && map.walkable_at(ny as usize, nx as usize) && !reached[ny as usize][nx as usize] {
+                reached[ny as usize][nx as usize] = true;
+                stack.push((ny as usize, nx as usize));
+            }
+        }
+    }
+    reached
+}
+
+/// Turns every floor tile outside the largest connected stretch of floor back into wall.
+fn keep_largest_region(map: &mut Map){
+    let mut largest: Option<(usize, Vec<Vec<bool>>)> = None;
+    let mut seen = vec![vec![false; map.cols]; map.rows];
+    for y in 0..map.rows{
+        for x in 0..map.cols{
+            if seen[y][x] || !map.walkable_at(y, x) {
+                continue;
+            }
+            let region = flood(map, (y, x));
+            let size = region.iter().flatten().filter(|&&tile| tile).count();
+            for (seen, tile) in seen.iter_mut().flatten().zip(region.iter().flatten()){
+                *seen |= *tile;
+            }
+            if largest.as_ref().is_none_or(|(most, _)| size > *most) {
+                largest = Some((size, region));
+            }
+        }
+    }
+    let wall = map.types.wall();
+    let keep = largest.map(|(_, region)| region);
+    for y in 0..map.rows{
+        for x in 0..map.cols{
+            if !keep.as_ref().is_some_and(|keep| keep[y][x]) {
+                map.set(y, x, wall);
+            }
+        }
+    }
+}
+
+/// Digs from `start` over to the floor it can't walk to yet, if there is any.
+fn connect(map: &mut Map, start: (usize, usize)){
+    let reached = flood(map, start);
+    let cut_off = |(y, x): (usize, usize)| [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|(dy, dx)| {
+        let (ny, nx) = (y as i32 + dy, x as i32 + dx);
+        map.is_on(ny, nx) && map.walkable_at(ny as usize, nx as usize) && !reached[ny as usize][nx as usize]
+    });
+    let no_gold = vec![vec![false; map.cols]; map.rows];
+    let tunnel = dig_tunnel(map, &no_gold, start, cut_off).unwrap_or_default();
+    let floor = map.types.floor();
+    for (y, x) in tunnel {
+        map.set(y, x, floor);
+    }
+}
+
+/// Cheapest 4-way tunnel from `from` to a tile where `arrived` holds, preferring to reuse open ground.
+/// Gold tiles are never dug through. Returns the tiles to clear, `None` if gold walls off every way there.
+fn dig_tunnel(map: &Map, is_gold: &[Vec<bool>], from: (usize, usize), arrived: impl Fn((usize, usize)) -> bool) -> Option<Vec<(usize, usize)>> {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+    const FLOOR_COST: u32 = 1;
+    const WALL_COST: u32 = 4;
+
+    let mut cost = vec![vec![u32::MAX; map.cols]; map.rows];
+    let mut came_from = vec![vec![None; map.cols]; map.rows];
+    let mut open = BinaryHeap::new();
+    cost[from.0][from.1] = 0;
+    open.push(Reverse((0, from)));
+
+    while let Some(Reverse((g, pos))) = open.pop() {
+        if g > cost[pos.0][pos.1] {
+            continue;
+        }
+        if arrived(pos) {
+            let mut tunnel = vec![pos];
+            let mut current = pos;
+            while let Some(prev) = came_from[current.0][current.1] {
+                tunnel.push(prev);
+                current = prev;
+            }
+            return Some(tunnel);
+        }
+        for (dr, dc) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let (nr, nc) = (pos.0 as i32 + dr, pos.1 as i32 + dc);
+            if !map.is_on(nr, nc) || is_gold[nr as usize][nc as usize] {
+                continue;
+            }
+            let next = (nr as usize, nc as usize);
+            let step = if map.walkable_at(next.0, next.1) { FLOOR_COST } else { WALL_COST };
+            if g + step < cost[next.0][next.1] {
+                cost[next.0][next.1] = g + step;
+                came_from[next.0][next.1] = Some(pos);
+                open.push(Reverse((g + step, next)));
+            }
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenMode {
+    /// solid rock with gold veins
+    Veins,
+    /// cellular automata caves, only the largest one kept, gold veins in the cave walls
+    Caves,
+}
+
 pub struct MapGenerator{
     pub rows: usize,
     pub cols: usize,
+    pub seed: u64,
+    pub mode: GenMode,
+
+    pub cave_fill: u32,// percent of tiles that start as wall
+    pub cave_smoothing: u32,// automata iterations
 
+    pub room_size: u32,
     pub gold_size: u32,
     pub gold_count: u32,
+    pub gold_amount: u32,
+
+    pub terrain_patches: u32,// patches of rubble and mud on open ground
+    pub terrain_size: u32,
+
+    pub monster_count: u32,// placed on open ground away from the starting room
+
+    pub food_count: u32,
+    pub food_amount: u32,
 }
 
 impl MapGenerator{
@@ -168,29 +465,96 @@ impl MapGenerator{
         Self{
             rows: rows.try_into().unwrap(),
             cols: cols.try_into().unwrap(),
-            gold_size: 0,
-            gold_count: 0
+            seed: 0,
+            mode: GenMode::Veins,
+            cave_fill: 45,
+            cave_smoothing: 5,
+            room_size: 10,
+            gold_size: 6,
+            gold_count: 24,
+            gold_amount: 25,
+            terrain_patches: 10,
+            terrain_size: 12,
+            monster_count: 4,
+            food_count: 4,
+            food_amount: 20,
         }
     }
     pub fn generate_blank(&self) -> Map{
         Map::new(self.rows, self.cols)
     }
 
-    pub fn generate(&self) -> Map{
-        let mut rand = RandomNumberGenerator::new();
+    /// Noise fill smoothed by the 4-5 rule.
+    fn generate_caves(&self, rand: &mut RandomNumberGenerator) -> Map{
         let mut map = self.generate_blank();
+        let (floor, wall) = (map.types.floor(), map.types.wall());
+        let border = |y: usize, x: usize| y == 0 || x == 0 || y == self.rows - 1 || x == self.cols - 1;
+        for y in 0..self.rows{
+            for x in 0..self.cols{
+                if !border(y, x) && rand.range(0, 100) >= self.cave_fill as i32 {
+                    map.set(y, x, floor);
+                }
+            }
+        }
 
-        let gold_tile = Tile {
-            fg: RGB::named(rltk::YELLOW),
-            bg: RGB::named(rltk::GREY),
-            ch: '#' as u16,
-            walkable: false
+        for _ in 0..self.cave_smoothing{
+            let mut next = map.clone();
+            for y in 0..self.rows{
+                for x in 0..self.cols{
+                    let mut walls = 0;
+                    for dy in -1..=1{
+                        for dx in -1..=1{
+                            let (ny, nx) = (y as i32 + dy, x as i32 + dx);
+                            if (dy, dx) != (0, 0) && (!map.is_on(ny, nx) || !map.walkable_at(ny as usize, nx as usize)) {
+                                walls += 1;
+                            }
+                        }
+                    }
+                    // walls survive with 4 wall neighbours, floor caves in with 5
+                    let is_wall = border(y, x) || walls >= 5 || (walls == 4 && !map.walkable_at(y, x));
+                    next.set(y, x, if is_wall { wall } else { floor });
+                }
+            }
+            map = next;
+        }
+        map
+    }
+
+    /// A cleared starting room in the middle and gold veins as deposit entities, in solid rock
+    /// or caves depending on `mode`. Veins only replace rock and every vein gets a tunnel to the
+    /// starting room, so all floor stays reachable. The same seed always gives the same map.
+    pub fn generate(&self, world: &mut World) -> Map{
+        let mut rand = RandomNumberGenerator::seeded(self.seed);
+
+        // spawn points and the stockpile need some room
+        let room_size = self.room_size.max(8);
+        let (room_r, room_c) = (self.rows as u32/2 - (room_size/2), self.cols as u32/2 - (room_size/2));
+        let (center_r, center_c) = (room_r + room_size/2, room_c + room_size/2);
+        let mut map = match self.mode {
+            GenMode::Veins => clear_room(self.generate_blank(), room_r, room_c, room_size, room_size),
+            GenMode::Caves => {
+                let mut caves = self.generate_caves(&mut rand);
+                keep_largest_region(&mut caves);
+                let mut map = clear_room(caves, room_r, room_c, room_size, room_size);
+                // the room can end up off in the rock
+                connect(&mut map, (center_r as usize, center_c as usize));
+                map
+            },
         };
+        let in_room = |y: usize, x: usize| (room_r as usize..(room_r + room_size) as usize).contains(&y)
+            && (room_c as usize..(room_c + room_size) as usize).contains(&x);
 
+        let mut is_gold = vec![vec![false; self.cols]; self.rows];
+        let mut veins = vec![];
         for _ in 0..self.gold_count{
             let (mut y, mut x) = (rand.range(1, self.rows as i32) , rand.range(1, self.cols as i32));
+            let mut vein = vec![];
             for _ in 0..=self.gold_size{
-                map.set(y.try_into().unwrap(), x.try_into().unwrap(), gold_tile);
+                let (vy, vx) = (y as usize, x as usize);
+                if !in_room(vy, vx) && !is_gold[vy][vx] && !map.walkable_at(vy, vx) {
+                    is_gold[vy][vx] = true;
+                    vein.push((vy, vx));
+                }
                 let (new_y, new_x) = match rand.range::<i32>(0, 4) {
                     0 => (y + 1, x),
                     1 => (y - 1, x),
@@ -203,13 +567,269 @@ impl MapGenerator{
                     x = new_x;
                 }
             }
+            if !vein.is_empty() {
+                veins.push(vein);
+            }
+        }
+
+        let floor = map.types.floor();
+        for vein in veins {
+            // straight next to it, a diagonal touch would leave the mined out vein cut off
+            let touches_vein = |(r, c): (usize, usize)| vein.iter().any(|&(vr, vc)| r.abs_diff(vr) + c.abs_diff(vc) == 1);
+            match dig_tunnel(&map, &is_gold, (center_r as usize, center_c as usize), touches_vein) {
+                Some(tunnel) => {
+                    for (y, x) in tunnel {
+                        map.set(y, x, floor);
+                    }
+                    for (y, x) in vein {
+                        map.set(y, x, floor);
+                        create_deposit(world, y as u32, x as u32, ResourceKind::Gold, self.gold_amount);
+                    }
+                },
+                None => for (y, x) in vein {
+                    is_gold[y][x] = false;
+                },
+            }
+        }
+
+        // slow ground only ever replaces floor, so nothing gets cut off
+        let slow: Vec<TileId> = ["rubble", "mud"].iter().filter_map(|name| map.types.id(name)).collect();
+        for _ in 0..if slow.is_empty() { 0 } else { self.terrain_patches }{
+            let kind = slow[rand.range(0, slow.len() as i32) as usize];
+            let (mut y, mut x) = (rand.range(1, self.rows as i32), rand.range(1, self.cols as i32));
+            for _ in 0..self.terrain_size{
+                let (py, px) = (y as usize, x as usize);
+                if !in_room(py, px) && map.vec[py][px] == floor {
+                    map.set(py, px, kind);
+                }
+                let (dy, dx) = [(1, 0), (-1, 0), (0, 1), (0, -1)][rand.range(0, 4) as usize];
+                if map.is_on(y + dy, x + dx) {
+                    y += dy;
+                    x += dx;
+                }
+            }
+        }
+
+        // monsters keep their distance from the start, gives up on maps with no open ground far enough out
+        let mut occupied = is_gold;
+        let mut placed = 0;
+        for _ in 0..self.monster_count * 100 {
+            if placed == self.monster_count {
+                break;
+            }
+            let (y, x) = (rand.range(1, self.rows as i32) as usize, rand.range(1, self.cols as i32) as usize);
+            let far = y.abs_diff(center_r as usize).max(x.abs_diff(center_c as usize)) >= room_size as usize;
+            if far && map.walkable_at(y, x) && !occupied[y][x] {
+                occupied[y][x] = true;
+                create_monster(world, y as u32, x as u32);
+                placed += 1;
+            }
+        }
+
+        map.spawns = vec![(center_r - 3, center_c), (center_r + 3, center_c), (center_r, center_c)];
+        let (stockpile, headquarters) = ((center_r, center_c + 3), (center_r, center_c - 3));
+        create_stockpile(world, stockpile.0, stockpile.1);
+        create_headquarters(world, headquarters.0, headquarters.1);
+
+        // food grows one tile in from the edge of the starting room, the edge itself stays open
+        // so no tunnel gets blocked off
+        let (inner_r, inner_c) = ((room_r + 1)..(room_r + room_size - 1), (room_c + 1)..(room_c + room_size - 1));
+        let mut placed = 0;
+        for _ in 0..self.food_count * 100 {
+            if placed == self.food_count {
+                break;
+            }
+            let (y, x) = (rand.range(inner_r.start as i32, inner_r.end as i32) as u32, rand.range(inner_c.start as i32, inner_c.end as i32) as u32);
+            let on_ring = y == inner_r.start || y == inner_r.end - 1 || x == inner_c.start || x == inner_c.end - 1;
+            if on_ring && !occupied[y as usize][x as usize] && !map.spawns.contains(&(y, x))
+                && (y, x) != stockpile && (y, x) != headquarters {
+                occupied[y as usize][x as usize] = true;
+                create_deposit(world, y, x, ResourceKind::Food, self.food_amount);
+                placed += 1;
+            }
+        }
+        map
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{WorldExt, Join};
+    use crate::Transform;
+    use crate::resource::ResourceDeposit;
+    use crate::MoveMap;
+    use crate::path::a_star;
+    use crate::sim::{Simulation, MapSource, Options};
+
+    const LEGEND: &str = "[legend]\n. = floor\nw = wall\n";
+
+    fn parse(text: &str) -> Result<Map, MapLoadError> {
+        Map::parse(text, &mut World::new())
+    }
+
+    fn parse_err(text: &str) -> MapLoadError {
+        parse(text).err().expect("map should not parse")
+    }
+
+    #[test]
+    fn small_map_parses() {
+        let map = parse(&format!("[meta]\nspawn = 1,1\n{}[map]\nwww\nw.w\nwww\n", LEGEND)).unwrap();
+        assert_eq!((map.rows(), map.cols()), (3, 3));
+        assert!(map.at(1, 1).walkable);
+        assert!(!map.at(0, 1).walkable);
+    }
+
+    #[test]
+    fn ragged_row_is_rejected() {
+        let err = parse_err(&format!("{}[map]\n...\n..\n", LEGEND));
+        assert!(matches!(err, MapLoadError::RaggedRow { line: 6, expected: 3, found: 2 }), "{}", err);
+    }
+
+    #[test]
+    fn unknown_glyph_is_rejected() {
+        let err = parse_err(&format!("{}[map]\n...\n.x.\n", LEGEND));
+        assert!(matches!(err, MapLoadError::UnknownGlyph { glyph: 'x', line: 6, column: 2 }), "{}", err);
+    }
+
+    #[test]
+    fn empty_map_section_is_rejected() {
+        let err = parse_err(&format!("{}[map]\n\n", LEGEND));
+        assert!(matches!(err, MapLoadError::BadDimensions { rows: 0, cols: 0 }), "{}", err);
+    }
+
+    #[test]
+    fn spawn_off_the_map_is_rejected() {
+        let err = parse_err(&format!("[meta]\nspawn = 3,0\n{}[map]\n...\n...\n", LEGEND));
+        assert!(matches!(err, MapLoadError::BadSpawn { r: 3, c: 0 }), "{}", err);
+    }
+
+    fn generated(mode: GenMode, seed: u64) -> (Map, Vec<(u32, u32, ResourceKind)>) {
+        let mut generator = MapGenerator::new(80, 50);
+        generator.mode = mode;
+        generator.seed = seed;
+        let sim = Simulation::with_map(1, MapSource::Generated(generator)).unwrap();
+        let mut deposits: Vec<_> = (&sim.ecs.read_storage::<Transform>(), &sim.ecs.read_storage::<ResourceDeposit>()).join()
+            .map(|(trans, deposit)| (trans.r, trans.c, deposit.kind))
+            .collect();
+        deposits.sort_by_key(|&(r, c, _)| (r, c));
+        let map = (*sim.ecs.fetch::<Map>()).clone();
+        (map, deposits)
+    }
+
+    // 4-way flood fill over walkable tiles, same as units move
+    fn reachable_from(map: &Map, start: (usize, usize)) -> Vec<Vec<bool>> {
+        let mut reached = vec![vec![false; map.cols]; map.rows];
+        let mut stack = vec![start];
+        reached[start.0][start.1] = true;
+        while let Some((r, c)) = stack.pop() {
+            for (dr, dc) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let (nr, nc) = (r as i32 + dr, c as i32 + dc);
+                if map.is_on(nr, nc) && map.walkable_at(nr as usize, nc as usize) && !reached[nr as usize][nc as usize] {
+                    reached[nr as usize][nc as usize] = true;
+                    stack.push((nr as usize, nc as usize));
+                }
+            }
+        }
+        reached
+    }
+
+    #[test]
+    fn caves_are_the_same_for_the_same_seed() {
+        let (first, first_deposits) = generated(GenMode::Caves, 7);
+        let (second, second_deposits) = generated(GenMode::Caves, 7);
+        assert_eq!(first.vec, second.vec);
+        assert_eq!(first_deposits, second_deposits);
+        assert!(first_deposits.iter().any(|&(_, _, kind)| kind == ResourceKind::Gold));
+    }
+
+    #[test]
+    fn seeded_start_is_repeatable_and_reaches_gold() {
+        let start = |seed: &str| {
+            let args = ["--generate", "--seed", seed].map(String::from);
+            let mut sim = Options::parse(args.into_iter()).unwrap().start().unwrap();
+            sim.step();
+            sim
+        };
+        let tiles = |sim: &Simulation| sim.ecs.fetch::<Map>().vec.clone();
+        let sim = start("11");
+        assert_eq!(tiles(&sim), tiles(&start("11")));
+        assert_ne!(tiles(&sim), tiles(&start("12")));
+
+        // the rest is solid rock, so only a tunnel gets from the middle of the starting room to the gold
+        let center = *sim.ecs.fetch::<Map>().spawn_points().last().unwrap();
+        let mmap = sim.ecs.fetch::<MoveMap>();
+        let (transforms, deposits) = (sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<ResourceDeposit>());
+        let reachable = (&transforms, &deposits).join()
+            .filter(|(_, deposit)| deposit.kind == ResourceKind::Gold)
+            .filter(|(trans, _)| a_star(&mmap, center, (trans.r, trans.c)).is_some())
+            .count();
+        assert!(reachable > 0, "no gold can be walked to");
+    }
+
+    #[test]
+    fn generated_maps_are_connected() {
+        for mode in [GenMode::Veins, GenMode::Caves] {
+            for seed in 0..5 {
+                let (map, deposits) = generated(mode, seed);
+                // the last spawn point is the middle of the starting room
+                let (center_r, center_c) = *map.spawn_points().last().unwrap();
+                let reached = reachable_from(&map, (center_r as usize, center_c as usize));
+                for (r, column) in reached.iter().enumerate() {
+                    for (c, reached) in column.iter().enumerate() {
+                        assert!(!map.walkable_at(r, c) || *reached, "{:?} seed {}: {},{} is cut off", mode, seed, r, c);
+                    }
+                }
+
+                // veins are the 8-way connected groups of gold, each needs open ground straight next to it
+                let gold: Vec<(u32, u32)> = deposits.iter()
+                    .filter(|&&(_, _, kind)| kind == ResourceKind::Gold)
+                    .map(|&(r, c, _)| (r, c))
+                    .collect();
+                let touching = |(r, c): (u32, u32), (or, oc): (u32, u32)| r.abs_diff(or) <= 1 && c.abs_diff(oc) <= 1;
+                let mut left = gold.clone();
+                while let Some(first) = left.pop() {
+                    let mut vein = vec![first];
+                    let mut i = 0;
+                    while i < vein.len() {
+                        let at = vein[i];
+                        let (near, far): (Vec<_>, Vec<_>) = left.into_iter().partition(|&other| touching(at, other));
+                        vein.extend(near);
+                        left = far;
+                        i += 1;
+                    }
+                    let open = vein.iter().any(|&(r, c)| [(1, 0), (-1, 0), (0, 1), (0, -1)].iter()
+                        .map(|(dr, dc)| (r as i32 + dr, c as i32 + dc))
+                        .any(|(nr, nc)| map.is_on(nr, nc) && reached[nr as usize][nc as usize] && !gold.contains(&(nr as u32, nc as u32))));
+                    assert!(open, "{:?} seed {}: vein at {:?} is walled in", mode, seed, vein[0]);
+                }
+            }
         }
+    }
 
-        const INIT_ROOM_SIZE: u32 = 10;
-        clear_room(map,
-            self.rows as u32/2 - (INIT_ROOM_SIZE/2),
-            self.cols as u32/2 - (INIT_ROOM_SIZE/2),
-            INIT_ROOM_SIZE, INIT_ROOM_SIZE)
+    #[test]
+    fn only_the_largest_cave_is_kept() {
+        let mut map = Map::new(10, 10);
+        let floor = map.types.floor();
+        // a 3x3 cave and a 2x2 one
+        for (r, c) in (1..4).flat_map(|r| (1..4).map(move |c| (r, c))).chain((6..8).flat_map(|r| (6..8).map(move |c| (r, c)))) {
+            map.set(r, c, floor);
+        }
+        keep_largest_region(&mut map);
+        assert!(map.at(2, 2).walkable);
+        assert!(!map.at(6, 6).walkable);
     }
 
+    #[test]
+    fn start_off_in_the_rock_gets_a_tunnel() {
+        let mut map = Map::new(12, 12);
+        let floor = map.types.floor();
+        for c in 1..11 {
+            map.set(1, c, floor);
+        }
+        map.set(8, 8, floor);
+        connect(&mut map, (8, 8));
+        assert!(flood(&map, (8, 8))[1][1]);
+    }
 }
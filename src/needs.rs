@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::System;
+use specs::{Read, WriteStorage};
+use specs::Join;
+
+use super::time::{Actor, SimClock, TICKS_PER_SECOND};
+use super::combat::Health;
+
+/// Needs go from 0, all good, up to this.
+pub const NEED_MAX: u32 = 100;
+// worth going for food or a nap from here on, with a stockpile and a bed around
+pub const HUNGRY_AT: u32 = 60;
+pub const TIRED_AT: u32 = 70;
+// hunger one food takes away
+pub const MEAL: u32 = 40;
+// fatigue one nap takes away, twice that in a bed
+pub const NAP: u32 = 10;
+
+// ticks for one point of each
+const HUNGER_TICKS: u64 = 2 * TICKS_PER_SECOND;
+const FATIGUE_TICKS: u64 = 3 * TICKS_PER_SECOND;
+// a starving worker loses a hit point this often
+const STARVE_TICKS: u64 = 5 * TICKS_PER_SECOND;
+// after failing to get to food it takes this long to try again
+const RETRY_TICKS: u64 = 5 * TICKS_PER_SECOND;
+
+/// Hunger and fatigue of a worker, both creep up over time.
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Needs {
+    pub hunger: u32,
+    pub fatigue: u32,
+    updated: u64,// tick it was last brought up to date
+    retry_eating_at: u64,
+}
+
+impl Needs {
+    pub fn new(now: u64) -> Self {
+        Self { hunger: 0, fatigue: 0, updated: now, retry_eating_at: 0 }
+    }
+    pub fn is_hungry(&self) -> bool {
+        self.hunger >= HUNGRY_AT
+    }
+    pub fn is_tired(&self) -> bool {
+        self.fatigue >= TIRED_AT
+    }
+    pub fn is_starving(&self) -> bool {
+        self.hunger >= NEED_MAX
+    }
+    pub fn is_exhausted(&self) -> bool {
+        self.fatigue >= NEED_MAX
+    }
+    pub fn can_eat(&self, now: u64) -> bool {
+        now >= self.retry_eating_at
+    }
+    pub fn put_off_eating(&mut self, now: u64) {
+        self.retry_eating_at = now + RETRY_TICKS;
+    }
+    pub fn eat(&mut self) {
+        self.hunger = self.hunger.saturating_sub(MEAL);
+    }
+    pub fn rest(&mut self, in_bed: bool) {
+        self.fatigue = self.fatigue.saturating_sub(if in_bed { NAP * 2 } else { NAP });
+    }
+}
+
+/// Somewhere to sleep, naps next to one do twice as much.
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Bed;
+
+/// Lets needs grow with the clock. Starving or exhausted workers slow down and the
+/// starving ones eventually die of it.
+pub struct NeedsManager;
+
+impl<'a> System<'a> for NeedsManager {
+    type SystemData = ( Read<'a, SimClock>,
+                        WriteStorage<'a, Needs>,
+                        WriteStorage<'a, Actor>,
+                        WriteStorage<'a, Health>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (clock, mut needs, mut actors, mut healths) = data;
+        let now = clock.now();
+
+        for (needs, actor, health) in (&mut needs, &mut actors, (&mut healths).maybe()).join() {
+            // whole points passed since the last update, so nothing gets lost to rounding
+            let since = needs.updated;
+            let passed = |every: u64| (now / every - since.min(now) / every) as u32;
+            needs.updated = now;
+
+            if needs.is_starving() {
+                if let Some(health) = health {
+                    health.hp -= passed(STARVE_TICKS) as i32;
+                }
+            }
+            needs.hunger = (needs.hunger + passed(HUNGER_TICKS)).min(NEED_MAX);
+            needs.fatigue = (needs.fatigue + passed(FATIGUE_TICKS)).min(NEED_MAX);
+            actor.set_slowed(needs.is_starving() || needs.is_exhausted());
+        }
+    }
+}
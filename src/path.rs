@@ -0,0 +1,275 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+
+use super::MoveMap;
+use super::tile::BASE_COST;
+
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+// groups spread wider than this are bunched up around the target instead of keeping their shape
+const FORMATION_MAX_SPREAD: u32 = 6;
+
+const NEIGHBOURS: [(i32, i32); 8] = [
+    (1, 0), (-1, 0), (0, 1), (0, -1),
+    (1, 1), (1, -1), (-1, 1), (-1, -1),
+];
+
+fn octile(from: (u32, u32), to: (u32, u32)) -> u32 {
+    let dr = from.0.abs_diff(to.0);
+    let dc = from.1.abs_diff(to.1);
+    let (short, long) = if dr < dc { (dr, dc) } else { (dc, dr) };
+    short * DIAGONAL_COST + (long - short) * STRAIGHT_COST
+}
+
+/// Walking distance on an open grid, in the same units as path costs.
+pub fn distance(from: (u32, u32), to: (u32, u32)) -> u32 {
+    octile(from, to)
+}
+
+pub fn is_adjacent(a: (u32, u32), b: (u32, u32)) -> bool {
+    a != b && a.0.abs_diff(b.0) <= 1 && a.1.abs_diff(b.1) <= 1
+}
+
+/// A* search over the `MoveMap` with 8-way movement (no cutting corners past blocked tiles).
+/// Slow terrain costs more to cross, so cheaper routes win when they are not much longer.
+/// Other units are ignored, they get out of the way or are waited on while walking.
+///
+/// Returns the tiles to walk through, excluding `start`. If `goal` itself is blocked
+/// (a deposit, a wall, ...) the path ends on a walkable tile next to it instead.
+/// `None` means there is no way to get there.
+pub fn a_star(mmap: &MoveMap, start: (u32, u32), goal: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    search(mmap, |r, c| mmap.is_walkable(r, c), start, goal)
+}
+
+/// Like `a_star`, but tiles with units on them count as blocked too. Used to get around a jam.
+pub fn a_star_around_units(mmap: &MoveMap, start: (u32, u32), goal: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    search(mmap, |r, c| mmap.is_walkable(r, c) && mmap.unit_at(r, c).is_none(), start, goal)
+}
+
+fn search(mmap: &MoveMap, is_walkable: impl Fn(i32, i32) -> bool, start: (u32, u32), goal: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    let goal_walkable = is_walkable(goal.0 as i32, goal.1 as i32);
+    let is_goal = |pos: (u32, u32)| pos == goal || (!goal_walkable && is_adjacent(pos, goal));
+    if is_goal(start) {
+        return Some(vec![]);
+    }
+    // when the goal is blocked we only need to reach a neighbour, and no step is cheaper than
+    // the cheapest terrain, both keep the heuristic admissible
+    let slack = if goal_walkable { 0 } else { DIAGONAL_COST };
+    let min_cost = mmap.min_cost();
+    let heuristic = |pos: (u32, u32)| octile(pos, goal).saturating_sub(slack) * min_cost / BASE_COST;
+
+    let index = |(r, c): (u32, u32)| r as usize * mmap.cols + c as usize;
+    let mut cost = vec![u32::MAX; mmap.rows * mmap.cols];
+    let mut came_from: Vec<Option<(u32, u32)>> = vec![None; mmap.rows * mmap.cols];
+    let mut open = BinaryHeap::new();
+
+    cost[index(start)] = 0;
+    open.push(Reverse((heuristic(start), 0, start)));
+
+    while let Some(Reverse((_, g, pos))) = open.pop() {
+        if g > cost[index(pos)] {
+            continue;
+        }
+        if is_goal(pos) {
+            let mut path = vec![pos];
+            let mut current = pos;
+            while let Some(prev) = came_from[index(current)] {
+                if prev == start {
+                    break;
+                }
+                path.push(prev);
+                current = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for (dr, dc) in NEIGHBOURS {
+            let (nr, nc) = (pos.0 as i32 + dr, pos.1 as i32 + dc);
+            if !is_walkable(nr, nc) {
+                continue;
+            }
+            let diagonal = dr != 0 && dc != 0;
+            if diagonal && !(is_walkable(pos.0 as i32 + dr, pos.1 as i32)
+                && is_walkable(pos.0 as i32, pos.1 as i32 + dc)) {
+                continue;
+            }
+            let next = (nr as u32, nc as u32);
+            let step = if diagonal { DIAGONAL_COST } else { STRAIGHT_COST };
+            let next_cost = g + step * mmap.move_cost(nr, nc) / BASE_COST;
+            if next_cost < cost[index(next)] {
+                cost[index(next)] = next_cost;
+                came_from[index(next)] = Some(pos);
+                open.push(Reverse((next_cost + heuristic(next), next_cost, next)));
+            }
+        }
+    }
+    None
+}
+
+/// Spreads a group move to `target` over distinct tiles, one for each of `starts` and in the same
+/// order. A tight group keeps its shape around the target, otherwise units pack in as close as
+/// they can. Only tiles connected to the target and not held by units outside the group are used.
+pub fn formation(mmap: &MoveMap, target: (u32, u32), starts: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    if starts.len() < 2 {
+        return vec![target; starts.len()];
+    }
+    let count = starts.len() as i64;
+    let center = (
+        (starts.iter().map(|s| s.0 as i64).sum::<i64>() / count) as i32,
+        (starts.iter().map(|s| s.1 as i64).sum::<i64>() / count) as i32,
+    );
+    let spread = starts.iter()
+        .map(|s| (s.0 as i32 - center.0).unsigned_abs().max((s.1 as i32 - center.1).unsigned_abs()))
+        .max().unwrap_or(0);
+    let keep_shape = spread <= FORMATION_MAX_SPREAD;
+    let wanted: Vec<(i32, i32)> = starts.iter().map(|s| match keep_shape {
+        true => (target.0 as i32 + s.0 as i32 - center.0, target.1 as i32 + s.1 as i32 - center.1),
+        false => (target.0 as i32, target.1 as i32),
+    }).collect();
+
+    // flood out from the target far enough to fit everyone, with some slack for walls
+    let radius = if keep_shape { spread } else { 0 } + (starts.len() as f32).sqrt().ceil() as u32 + 2;
+    let group: HashSet<(u32, u32)> = starts.iter().copied().collect();
+    let free = |r: i32, c: i32| mmap.is_walkable(r, c)
+        && (mmap.unit_at(r, c).is_none() || group.contains(&(r as u32, c as u32)));
+    let mut candidates = vec![];
+    let mut seen = HashSet::from([target]);
+    let mut queue = VecDeque::from([target]);
+    while let Some(pos) = queue.pop_front() {
+        if free(pos.0 as i32, pos.1 as i32) {
+            candidates.push(pos);
+        }
+        for (dr, dc) in NEIGHBOURS {
+            let (nr, nc) = (pos.0 as i32 + dr, pos.1 as i32 + dc);
+            if !mmap.is_walkable(nr, nc) {
+                continue;
+            }
+            let next = (nr as u32, nc as u32);
+            if next.0.abs_diff(target.0).max(next.1.abs_diff(target.1)) <= radius && seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+
+    // whoever wants a spot closest to the target picks first
+    let mut order: Vec<usize> = (0..starts.len()).collect();
+    order.sort_by_key(|&i| (wanted[i].0 - target.0 as i32).unsigned_abs() + (wanted[i].1 - target.1 as i32).unsigned_abs());
+    let mut taken = vec![false; candidates.len()];
+    let mut destinations = vec![target; starts.len()];
+    for i in order {
+        let (r, c) = wanted[i];
+        let best = (0..candidates.len())
+            .filter(|&k| !taken[k])
+            .min_by_key(|&k| {
+                let (dr, dc) = (candidates[k].0 as i32 - r, candidates[k].1 as i32 - c);
+                dr * dr + dc * dc
+            });
+        if let Some(k) = best {
+            taken[k] = true;
+            destinations[i] = candidates[k];
+        }
+    }
+    destinations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an open map with the given tiles blocked
+    fn map_with_walls(rows: usize, cols: usize, walls: &[(usize, usize)]) -> MoveMap {
+        let mut mmap = MoveMap::new(rows, cols);
+        for &(r, c) in walls {
+            mmap.map[r][c] = false;
+        }
+        mmap
+    }
+
+    // every step is next to the one before and walkable
+    fn assert_walkable_path(mmap: &MoveMap, start: (u32, u32), path: &[(u32, u32)]) {
+        let mut from = start;
+        for &step in path {
+            assert!(is_adjacent(from, step), "{:?} to {:?} is not a single step", from, step);
+            assert!(mmap.is_walkable(step.0 as i32, step.1 as i32), "{:?} is blocked", step);
+            from = step;
+        }
+    }
+
+    #[test]
+    fn routes_around_a_wall() {
+        let walls: Vec<_> = (0..4).map(|c| (2, c)).collect();
+        let mmap = map_with_walls(5, 5, &walls);
+        let path = a_star(&mmap, (0, 0), (4, 0)).expect("there is a way round");
+        assert_eq!(path.last(), Some(&(4, 0)));
+        assert_walkable_path(&mmap, (0, 0), &path);
+        assert!(path.contains(&(2, 4)));
+    }
+
+    #[test]
+    fn does_not_cut_corners() {
+        let mmap = map_with_walls(3, 3, &[(0, 1)]);
+        let path = a_star(&mmap, (0, 0), (1, 1)).expect("reachable");
+        assert_eq!(path, vec![(1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn blocked_goal_ends_next_to_it() {
+        let mmap = map_with_walls(5, 5, &[(2, 2)]);
+        let path = a_star(&mmap, (0, 0), (2, 2)).expect("a neighbour is reachable");
+        let last = *path.last().expect("start is not next to the goal");
+        assert!(is_adjacent(last, (2, 2)));
+        assert_walkable_path(&mmap, (0, 0), &path);
+    }
+
+    #[test]
+    fn enclosed_goal_is_unreachable() {
+        let ring: Vec<_> = NEIGHBOURS.iter().map(|(dr, dc)| ((2 + dr) as usize, (2 + dc) as usize)).collect();
+        let mmap = map_with_walls(5, 5, &ring);
+        assert_eq!(a_star(&mmap, (0, 0), (2, 2)), None);
+    }
+
+    fn assert_distinct(tiles: &[(u32, u32)]) {
+        let unique: HashSet<_> = tiles.iter().collect();
+        assert_eq!(unique.len(), tiles.len(), "{:?} shares tiles", tiles);
+    }
+
+    #[test]
+    fn tight_group_keeps_its_shape() {
+        let mmap = map_with_walls(30, 30, &[]);
+        let starts = [(5, 5), (6, 5), (5, 7), (7, 8)];
+        let destinations = formation(&mmap, (20, 20), &starts);
+        // the group centre is (5, 6)
+        assert_eq!(destinations, vec![(20, 19), (21, 19), (20, 21), (22, 22)]);
+    }
+
+    #[test]
+    fn formation_works_around_walls_and_other_units() {
+        let mut mmap = map_with_walls(30, 30, &[(20, 19)]);
+        let stranger = {
+            use specs::WorldExt;
+            specs::World::new().entities().create()
+        };
+        mmap.units.insert((21, 19), stranger);
+        let starts = [(5, 5), (6, 5), (5, 7), (7, 8)];
+        let destinations = formation(&mmap, (20, 20), &starts);
+        assert_distinct(&destinations);
+        for &(r, c) in &destinations {
+            assert!(mmap.is_walkable(r as i32, c as i32) && mmap.unit_at(r as i32, c as i32).is_none(), "{},{} is taken", r, c);
+        }
+        // the ones with a free spot still get it
+        assert_eq!(&destinations[2..], &[(20, 21), (22, 22)]);
+    }
+
+    #[test]
+    fn spread_out_group_packs_in() {
+        let mmap = map_with_walls(30, 30, &[]);
+        let starts = [(0, 0), (0, 20), (20, 0), (20, 20)];
+        let destinations = formation(&mmap, (10, 10), &starts);
+        assert_distinct(&destinations);
+        for &(r, c) in &destinations {
+            assert!(r.abs_diff(10).max(c.abs_diff(10)) <= 1, "{},{} is too far out", r, c);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ResourceKind {
+    Gold,
+    Stone,
+    Food,
+}
+
+impl ResourceKind {
+    pub const ALL: [ResourceKind; 3] = [ResourceKind::Gold, ResourceKind::Stone, ResourceKind::Food];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gold" => Some(ResourceKind::Gold),
+            "stone" => Some(ResourceKind::Stone),
+            "food" => Some(ResourceKind::Food),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceKind::Gold => "gold",
+            ResourceKind::Stone => "stone",
+            ResourceKind::Food => "food",
+        }
+    }
+
+    /// How a deposit or pile of this looks on the map.
+    pub fn glyph(&self) -> (u16, rltk::RGB) {
+        match self {
+            ResourceKind::Gold => ('M' as u16, rltk::RGB::named(rltk::BLUE)),
+            ResourceKind::Stone => ('%' as u16, rltk::RGB::named(rltk::LIGHT_GRAY)),
+            ResourceKind::Food => ('"' as u16, rltk::RGB::named(rltk::LIME_GREEN)),
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct ResourceDeposit {
+    pub kind: ResourceKind,
+    pub remaining: u32,
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Inventory {
+    items: HashMap<ResourceKind, u32>,
+    capacity: u32,
+}
+
+impl Inventory {
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            items: HashMap::new(),
+            capacity,
+        }
+    }
+    /// Adds as much as fits and returns how much was actually taken.
+    pub fn add(&mut self, kind: ResourceKind, amount: u32) -> u32 {
+        let amount = amount.min(self.space());
+        *self.items.entry(kind).or_insert(0) += amount;
+        amount
+    }
+    /// Removes up to `amount` of `kind` and returns how much there was.
+    pub fn take(&mut self, kind: ResourceKind, amount: u32) -> u32 {
+        let held = self.items.entry(kind).or_insert(0);
+        let taken = amount.min(*held);
+        *held -= taken;
+        taken
+    }
+    pub fn take_all(&mut self) -> HashMap<ResourceKind, u32> {
+        std::mem::take(&mut self.items)
+    }
+    pub fn count(&self, kind: ResourceKind) -> u32 {
+        self.items.get(&kind).copied().unwrap_or(0)
+    }
+    pub fn total(&self) -> u32 {
+        self.items.values().sum()
+    }
+    pub fn space(&self) -> u32 {
+        self.capacity.saturating_sub(self.total())
+    }
+    pub fn is_full(&self) -> bool {
+        self.space() == 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+}
+
+/// Drop-off point for hauled resources.
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Stockpile;
+
+/// Everything delivered to stockpiles so far.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Resources {
+    amounts: HashMap<ResourceKind, u32>,
+}
+
+impl Resources {
+    pub fn add(&mut self, kind: ResourceKind, amount: u32) {
+        *self.amounts.entry(kind).or_insert(0) += amount;
+    }
+    pub fn get(&self, kind: ResourceKind) -> u32 {
+        self.amounts.get(&kind).copied().unwrap_or(0)
+    }
+    /// Removes up to `amount` of `kind` and returns how much there was.
+    pub fn take(&mut self, kind: ResourceKind, amount: u32) -> u32 {
+        let stored = self.amounts.entry(kind).or_insert(0);
+        let taken = amount.min(*stored);
+        *stored -= taken;
+        taken
+    }
+}
@@ -0,0 +1,366 @@
+use std::fmt;
+use std::path::Path;
+
+use rltk::RandomNumberGenerator;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use specs::{World, WorldExt, Join, Entity, Component};
+use specs::saveload::{ConvertSaveload, Marker, MarkerAllocator};
+use specs::saveload::{SimpleMarker, SimpleMarkerAllocator};
+use specs::error::NoError;
+
+use super::{Transform, Worker, WorkerTask, MoveMap};
+use super::map::Map;
+use super::time::{Actor, SimClock};
+use super::input::Selectable;
+use super::resource::{ResourceDeposit, Inventory, Stockpile, Resources};
+use super::vision::Viewshed;
+use super::job::{JobBoard, JobBoardData};
+use super::build::{Blueprint, Workshop};
+use super::combat::{Health, Faction, CombatStats};
+use super::ai::Monster;
+use super::needs::{Needs, Bed};
+use super::skill::Skills;
+use super::hq::Headquarters;
+
+/// Bump this whenever the save layout changes, old saves are refused instead of half loaded.
+pub const SAVE_VERSION: u64 = 14;
+
+/// Marker for entities that go into save files.
+pub struct SerializeMe;
+
+pub type SaveMarker = SimpleMarker<SerializeMe>;
+pub type SaveMarkerAllocator = SimpleMarkerAllocator<SerializeMe>;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    Version { found: Option<u64>, expected: u64 },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(err) => write!(f, "could not access save file: {}", err),
+            SaveError::Format(err) => write!(f, "save file is corrupt: {}", err),
+            SaveError::Version { found: Some(found), expected } =>
+                write!(f, "save file is version {}, this build only loads version {}", found, expected),
+            SaveError::Version { found: None, expected } =>
+                write!(f, "save file has no version header, this build only loads version {}", expected),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(err: serde_json::Error) -> Self {
+        SaveError::Format(err)
+    }
+}
+
+#[derive(Serialize)]
+struct SaveFile<'a> {
+    version: u64,
+    clock: &'a SimClock,
+    rng: &'a RandomNumberGenerator,
+    resources: &'a Resources,
+    map: &'a Map,
+    move_map: &'a MoveMap,
+    jobs: JobBoardData<SaveMarker>,
+    entities: serde_json::Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct LoadFile {
+    clock: SimClock,
+    rng: RandomNumberGenerator,
+    resources: Resources,
+    map: Map,
+    move_map: MoveMap,
+    jobs: JobBoardData<SaveMarker>,
+    entities: serde_json::Map<String, Value>,
+}
+
+// Saved form of a Worker, entities are replaced by their save markers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorkerData<M> {
+    task: WorkerTaskData<M>,
+    queue: Vec<WorkerTaskData<M>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum WorkerTaskData<M> {
+    Idle,
+    Mine(M),
+    MoveTo(u32, u32),
+    Dig(u32, u32),
+    Build(M),
+    Attack(M),
+    Eat,
+    Sleep,
+}
+
+impl<M: Marker> ConvertSaveload<M> for Worker {
+    type Data = WorkerData<M>;
+    type Error = NoError;
+
+    fn convert_into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+    where
+        F: FnMut(Entity) -> Option<M>,
+    {
+        // a task on an entity that is already gone is dropped
+        let mut convert = |task: &WorkerTask| match *task {
+            WorkerTask::Idle => Some(WorkerTaskData::Idle),
+            WorkerTask::Mine(target) => ids(target).map(WorkerTaskData::Mine),
+            WorkerTask::MoveTo(r, c) => Some(WorkerTaskData::MoveTo(r, c)),
+            WorkerTask::Dig(r, c) => Some(WorkerTaskData::Dig(r, c)),
+            WorkerTask::Build(target) => ids(target).map(WorkerTaskData::Build),
+            WorkerTask::Attack(target) => ids(target).map(WorkerTaskData::Attack),
+            WorkerTask::Eat => Some(WorkerTaskData::Eat),
+            WorkerTask::Sleep => Some(WorkerTaskData::Sleep),
+        };
+        let task = convert(&self.task).unwrap_or(WorkerTaskData::Idle);
+        let queue = self.queue.iter().filter_map(convert).collect();
+        Ok(WorkerData { task, queue })
+    }
+
+    fn convert_from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+    where
+        F: FnMut(M) -> Option<Entity>,
+    {
+        let mut convert = |task: WorkerTaskData<M>| match task {
+            WorkerTaskData::Idle => Some(WorkerTask::Idle),
+            WorkerTaskData::Mine(target) => ids(target).map(WorkerTask::Mine),
+            WorkerTaskData::MoveTo(r, c) => Some(WorkerTask::MoveTo(r, c)),
+            WorkerTaskData::Dig(r, c) => Some(WorkerTask::Dig(r, c)),
+            WorkerTaskData::Build(target) => ids(target).map(WorkerTask::Build),
+            WorkerTaskData::Attack(target) => ids(target).map(WorkerTask::Attack),
+            WorkerTaskData::Eat => Some(WorkerTask::Eat),
+            WorkerTaskData::Sleep => Some(WorkerTask::Sleep),
+        };
+        let task = convert(data.task).unwrap_or(WorkerTask::Idle);
+        let queue = data.queue.into_iter().filter_map(convert).collect();
+        Ok(Worker { task, queue, stale_action: false })
+    }
+}
+
+fn save_section<C>(ecs: &World) -> Result<Value, serde_json::Error>
+where
+    C: Component + ConvertSaveload<SaveMarker, Error = NoError>,
+{
+    let storage = ecs.read_storage::<C>();
+    let markers = ecs.read_storage::<SaveMarker>();
+    let mut rows = vec![];
+    for (component, marker) in (&storage, &markers).join() {
+        let data = match component.convert_into(|entity| markers.get(entity).copied()) {
+            Ok(data) => data,
+            Err(never) => match never {},
+        };
+        rows.push((*marker, data));
+    }
+    serde_json::to_value(rows)
+}
+
+fn load_section<C>(ecs: &World, section: Value) -> Result<(), serde_json::Error>
+where
+    C: Component + ConvertSaveload<SaveMarker, Error = NoError>,
+{
+    let rows: Vec<(SaveMarker, C::Data)> = serde_json::from_value(section)?;
+    let entities = ecs.entities();
+    let mut storage = ecs.write_storage::<C>();
+    let mut markers = ecs.write_storage::<SaveMarker>();
+    let mut allocator = ecs.write_resource::<SaveMarkerAllocator>();
+    for (marker, data) in rows {
+        let entity = allocator.retrieve_entity(marker, &mut markers, &entities);
+        let component = match C::convert_from(data, |marker| Some(allocator.retrieve_entity(marker, &mut markers, &entities))) {
+            Ok(component) => component,
+            Err(never) => match never {},
+        };
+        storage.insert(entity, component).expect("entity from the marker allocator is alive");
+    }
+    Ok(())
+}
+
+macro_rules! save_components {
+    ($ecs:expr, $sections:expr, $( $type:ty ),*) => {
+        $( $sections.insert(stringify!($type).to_string(), save_section::<$type>($ecs)?); )*
+    };
+}
+
+macro_rules! load_components {
+    ($ecs:expr, $sections:expr, $( $type:ty ),*) => {
+        $(
+        if let Some(section) = $sections.remove(stringify!($type)) {
+            load_section::<$type>($ecs, section)?;
+        }
+        )*
+    };
+}
+
+// Every component type that goes into save files.
+macro_rules! with_saved_components {
+    ($apply:ident, $ecs:expr, $sections:expr) => {
+        $apply!($ecs, $sections,
+            Transform, Worker, Actor, Selectable, ResourceDeposit, Inventory, Stockpile, Viewshed,
+            Blueprint, Workshop, Health, Faction, CombatStats, Monster, Needs, Bed, Skills,
+            Headquarters)
+    };
+}
+
+/// Writes every entity plus the simulation resources to `path` as JSON.
+/// Entities are given save markers on the way, so references between them survive the trip.
+pub fn save_world(ecs: &World, path: impl AsRef<Path>) -> Result<(), SaveError> {
+    {
+        let entities = ecs.entities();
+        let mut markers = ecs.write_storage::<SaveMarker>();
+        let mut allocator = ecs.write_resource::<SaveMarkerAllocator>();
+        for entity in entities.join() {
+            allocator.mark(entity, &mut markers);
+        }
+    }
+
+    let mut sections = serde_json::Map::new();
+    with_saved_components!(save_components, ecs, sections);
+    let jobs = {
+        let markers = ecs.read_storage::<SaveMarker>();
+        match ecs.fetch::<JobBoard>().convert_into(|entity| markers.get(entity).copied()) {
+            Ok(jobs) => jobs,
+            Err(never) => match never {},
+        }
+    };
+
+    let save = SaveFile {
+        version: SAVE_VERSION,
+        clock: &ecs.fetch::<SimClock>(),
+        rng: &ecs.fetch::<RandomNumberGenerator>(),
+        resources: &ecs.fetch::<Resources>(),
+        map: &ecs.fetch::<Map>(),
+        move_map: &ecs.fetch::<MoveMap>(),
+        jobs,
+        entities: sections,
+    };
+    std::fs::write(path, serde_json::to_string(&save)?)?;
+    Ok(())
+}
+
+/// Fills a freshly registered world from a save written by `save_world`.
+pub fn load_world(ecs: &mut World, path: impl AsRef<Path>) -> Result<(), SaveError> {
+    let value: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let found = value.get("version").and_then(Value::as_u64);
+    if found != Some(SAVE_VERSION) {
+        return Err(SaveError::Version { found, expected: SAVE_VERSION });
+    }
+    let save: LoadFile = serde_json::from_value(value)?;
+
+    ecs.insert(save.clock);
+    ecs.insert(save.rng);
+    ecs.insert(save.resources);
+    ecs.insert(save.map);
+    ecs.insert(save.move_map);
+
+    let mut sections = save.entities;
+    with_saved_components!(load_components, ecs, sections);
+    // the board points at entities, so it comes in after them
+    let jobs = {
+        let entities = ecs.entities();
+        let mut markers = ecs.write_storage::<SaveMarker>();
+        let mut allocator = ecs.write_resource::<SaveMarkerAllocator>();
+        match JobBoard::convert_from(save.jobs, |marker| Some(allocator.retrieve_entity(marker, &mut markers, &entities))) {
+            Ok(jobs) => jobs,
+            Err(never) => match never {},
+        }
+    };
+    ecs.insert(jobs);
+    ecs.maintain();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::resource::ResourceKind;
+    use crate::sim::Simulation;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rogue-{}-{}.json", name, std::process::id()))
+    }
+
+    // the whole save with every section in marker order, entity ids don't matter
+    fn snapshot(sim: &Simulation, path: &Path) -> Value {
+        sim.save(path).unwrap();
+        let mut value: Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        for section in value["entities"].as_object_mut().unwrap().values_mut() {
+            section.as_array_mut().unwrap().sort_by_key(|row| row[0].as_u64());
+        }
+        value
+    }
+
+    fn remaining(sim: &Simulation, deposit: Entity) -> u32 {
+        sim.ecs.read_storage::<ResourceDeposit>().get(deposit).unwrap().remaining
+    }
+
+    #[test]
+    fn worker_keeps_mining_after_a_load() {
+        let mut sim = Simulation::new(4);
+        let (worker, deposit) = {
+            let entities = sim.ecs.entities();
+            let (transforms, workers, deposits) = (sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<Worker>(), sim.ecs.read_storage::<ResourceDeposit>());
+            let (worker, at) = (&entities, &transforms, &workers).join().map(|(e, trans, _)| (e, (trans.r, trans.c))).next().unwrap();
+            let (deposit, _) = (&entities, &transforms, &deposits).join()
+                .filter(|(_, _, deposit)| deposit.kind == ResourceKind::Gold)
+                .map(|(e, trans, _)| (e, trans.r.abs_diff(at.0).max(trans.c.abs_diff(at.1))))
+                .min_by_key(|&(_, distance)| distance)
+                .unwrap();
+            (worker, deposit)
+        };
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::Mine(deposit));
+
+        // one load mined out and the next one under way
+        let full = remaining(&sim, deposit);
+        for _ in 0..2000 {
+            if remaining(&sim, deposit) < full {
+                break;
+            }
+            sim.step();
+        }
+        assert!(remaining(&sim, deposit) < full, "worker never got to the deposit");
+        sim.step_n(3);
+        assert!(matches!(sim.ecs.read_storage::<Worker>().get(worker).unwrap().task, WorkerTask::Mine(target) if target == deposit));
+
+        let path = temp_file("mid-mine");
+        sim.save(&path).unwrap();
+        let mut loaded = Simulation::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let before = remaining(&sim, deposit);
+        sim.step_n(200);
+        loaded.step_n(200);
+        assert!(remaining(&sim, deposit) < before, "mining stopped");
+        assert_eq!(snapshot(&sim, &temp_file("original")), snapshot(&loaded, &temp_file("loaded")));
+    }
+
+    #[test]
+    fn old_version_is_refused() {
+        let sim = Simulation::new(0);
+        let path = temp_file("old-version");
+        sim.save(&path).unwrap();
+        let mut value: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        value["version"] = Value::from(SAVE_VERSION - 1);
+        std::fs::write(&path, value.to_string()).unwrap();
+
+        let result = Simulation::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(SaveError::Version { found: Some(found), expected: SAVE_VERSION }) if found == SAVE_VERSION - 1));
+    }
+}
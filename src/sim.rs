@@ -0,0 +1,467 @@
+use rltk::RandomNumberGenerator;
+use std::path::{Path, PathBuf};
+
+use specs::{World, WorldExt, Builder, Join, Entity};
+use specs::RunNow;
+
+use super::{Transform, Worker, MoveMap, WorkManager, MapManager};
+use super::map::{Map, MapLoadError, MapGenerator, GenMode, MAX_MAP_SIZE};
+use super::time::{self, Actor, SimClock};
+use super::input::{self, Selectable, MouseEvent, MouseEventT, KeyEvent, IsSomeSelected, QueueOrders};
+use super::resource::{ResourceKind, ResourceDeposit, Inventory, Stockpile, Resources};
+use super::save::{self, SaveError, SaveMarker, SaveMarkerAllocator};
+use super::vision::{Viewshed, VisibilitySystem};
+use super::job::{JobBoard, JobAssigner};
+use super::build::{Blueprint, Workshop, BuildingCatalog, ConstructionManager};
+use super::combat::{Health, Faction, CombatStats, DeathSystem};
+use super::ai::{Monster, MonsterAI};
+use super::needs::{Needs, Bed, NeedsManager};
+use super::skill::Skills;
+use super::hq::{Headquarters, RecruitManager};
+
+const WORKER_CAPACITY: u32 = 10;
+const WORKER_SIGHT: i32 = 8;
+const WORKER_HEALTH: i32 = 20;
+const MONSTER_HEALTH: i32 = 12;
+const MONSTER_SIGHT: i32 = 6;
+const MONSTER_LEASH: u32 = 12;
+// enough to get the first food hauled in
+const STARTING_FOOD: u32 = 10;
+
+pub enum MapSource {
+    Basic,
+    File(PathBuf),
+    Generated(MapGenerator),
+}
+
+/// What `Options::parse` takes, for the usage lines of the game and the headless runner.
+pub const OPTIONS_USAGE: &str = "[--seed N] [--frames N] [--size RxC] [--load SAVE_FILE | --generate | --caves | MAP_FILE]";
+
+/// Startup options shared by the game and the headless runner.
+pub struct Options {
+    pub seed: u64,
+    pub map: MapSource,
+    pub load: Option<PathBuf>,
+    pub frames: u32,// headless only
+}
+
+impl Options {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.peekable();
+        let mut options = Options { seed: 0, map: MapSource::Basic, load: None, frames: 300 };
+        let mut generate = None;
+        let mut size = None;
+        // --load, --generate, --caves and a map file each say where the world comes from, only one can
+        let mut source = None;
+        let mut pick = |what: &'static str| match source.replace(what) {
+            Some(first) => Err(format!("{} and {} can't be used together", first, what)),
+            None => Ok(()),
+        };
+        while let Some(arg) = args.next() {
+            let mut number = |name: &str| -> Result<u64, String> {
+                let value = args.next().ok_or(format!("{} needs a value", name))?;
+                value.parse().map_err(|_| format!("{} must be a number, got '{}'", name, value))
+            };
+            match arg.as_str() {
+                "--seed" => options.seed = number("--seed")?,
+                "--frames" => {
+                    let frames = number("--frames")?;
+                    options.frames = u32::try_from(frames).map_err(|_| format!("--frames must be at most {}, got '{}'", u32::MAX, frames))?;
+                },
+                "--generate" => {
+                    pick("--generate")?;
+                    generate = Some(GenMode::Veins);
+                },
+                "--caves" => {
+                    pick("--caves")?;
+                    generate = Some(GenMode::Caves);
+                },
+                "--size" => size = Some(parse_size(&args.next().ok_or("--size needs a value")?)?),
+                "--load" => {
+                    pick("--load")?;
+                    options.load = Some(args.next().ok_or("--load needs a file")?.into());
+                },
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => {
+                    pick("a map file")?;
+                    options.map = MapSource::File(arg.into());
+                },
+            }
+        }
+        if let Some(mode) = generate {
+            let (rows, cols) = size.unwrap_or((80, 50));
+            let mut generator = MapGenerator::new(rows, cols);
+            generator.seed = options.seed;
+            generator.mode = mode;
+            // keep about the same amount of gold per tile as the default size
+            generator.gold_count = generator.gold_count * rows * cols / (80 * 50);
+            options.map = MapSource::Generated(generator);
+        } else if size.is_some() {
+            return Err("--size only works with --generate or --caves".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Builds the simulation these options ask for.
+    pub fn start(self) -> Result<Simulation, String> {
+        match self.load {
+            Some(path) => Simulation::load(path).map_err(|err| format!("error loading save: {}", err)),
+            None => Simulation::with_map(self.seed, self.map).map_err(|err| format!("error loading map: {}", err)),
+        }
+    }
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let bad_size = || format!("--size must look like 256x256, got '{}'", value);
+    let (rows, cols) = value.split_once('x').ok_or_else(bad_size)?;
+    let (rows, cols): (u32, u32) = (rows.parse().map_err(|_| bad_size())?, cols.parse().map_err(|_| bad_size())?);
+    // the generator needs room for the starting area
+    if !(20..=MAX_MAP_SIZE as u32).contains(&rows) || !(20..=MAX_MAP_SIZE as u32).contains(&cols) {
+        return Err(format!("--size must be between 20 and {} each way", MAX_MAP_SIZE));
+    }
+    Ok((rows, cols))
+}
+
+fn register_components(world: &mut World) {
+    world.register::<Actor>();
+    world.register::<Transform>();
+    world.register::<Worker>();
+    world.register::<Selectable>();
+    world.register::<ResourceDeposit>();
+    world.register::<Inventory>();
+    world.register::<Stockpile>();
+    world.register::<Viewshed>();
+    world.register::<Blueprint>();
+    world.register::<Workshop>();
+    world.register::<Health>();
+    world.register::<Faction>();
+    world.register::<CombatStats>();
+    world.register::<Monster>();
+    world.register::<Needs>();
+    world.register::<Bed>();
+    world.register::<Skills>();
+    world.register::<Headquarters>();
+    world.register::<SaveMarker>();
+    world.insert(SaveMarkerAllocator::new());
+    world.insert(BuildingCatalog::builtin());
+}
+
+/// The whole game world without any window attached. The rltk frontend and the
+/// headless runner both drive it through `step`.
+pub struct Simulation {
+    pub ecs: World,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self::with_map(seed, MapSource::Basic).expect("built in map is broken")
+    }
+
+    pub fn with_map(seed: u64, source: MapSource) -> Result<Self, MapLoadError> {
+        Self::build(seed, |world| match source {
+            MapSource::Basic => Ok(Map::basic_80x50(world)),
+            MapSource::File(path) => Map::from_file(path, world),
+            MapSource::Generated(generator) => Ok(generator.generate(world)),
+        })
+    }
+
+    /// A small hand written map for tests, in the map file layout.
+    #[cfg(test)]
+    pub(crate) fn from_text(seed: u64, text: &str) -> Self {
+        Self::build(seed, |world| Map::parse(text, world)).expect("test map is broken")
+    }
+
+    fn build(seed: u64, make_map: impl FnOnce(&mut World) -> Result<Map, MapLoadError>) -> Result<Self, MapLoadError> {
+        let mut world = World::new();
+        register_components(&mut world);
+
+        let map = make_map(&mut world)?;
+        let (rows, cols) = (map.rows(), map.cols());
+
+        // RESOURCES
+        world.insert(SimClock::default());
+        world.insert(RandomNumberGenerator::seeded(seed));
+
+        for &(r, c) in map.spawn_points() {
+            create_worker(&mut world, r, c);
+        }
+
+        let mut resources = Resources::default();
+        resources.add(ResourceKind::Food, STARTING_FOOD);
+        world.insert(resources);
+        world.insert(JobBoard::default());
+        world.insert(MouseEvent(MouseEventT::Empty));
+        world.insert(KeyEvent::default());
+        world.insert(IsSomeSelected(false));
+        world.insert(QueueOrders(false));
+        world.insert(MoveMap::new(rows as usize, cols as usize));
+        world.insert(map);
+
+        Ok(Self { ecs: world })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SaveError> {
+        save::save_world(&self.ecs, path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SaveError> {
+        let mut world = World::new();
+        register_components(&mut world);
+        save::load_world(&mut world, path)?;
+
+        let some_selected = world.read_storage::<Selectable>().join().any(|s| s.selected);
+        world.insert(MouseEvent(MouseEventT::Empty));
+        world.insert(KeyEvent::default());
+        world.insert(IsSomeSelected(some_selected));
+        world.insert(QueueOrders(false));
+        Ok(Self { ecs: world })
+    }
+
+    /// Runs every system once and advances the clock by one frame worth of ticks.
+    pub fn step(&mut self) {
+        input::run_systems(&self.ecs);
+        let mut map_manager = MapManager{};
+        let mut jobs = JobAssigner{};
+        let mut wm = WorkManager{};
+        let mut monster_ai = MonsterAI{};
+        let mut tm = time::TimeManager{};
+        let mut construction = ConstructionManager{};
+        let mut recruits = RecruitManager{};
+        let mut needs = NeedsManager{};
+        let mut deaths = DeathSystem{};
+        let mut vis = VisibilitySystem{};
+        map_manager.run_now(&self.ecs);
+        jobs.run_now(&self.ecs);
+        wm.run_now(&self.ecs);
+        monster_ai.run_now(&self.ecs);
+        tm.run_now(&self.ecs);
+        construction.run_now(&self.ecs);
+        recruits.run_now(&self.ecs);
+        needs.run_now(&self.ecs);
+        deaths.run_now(&self.ecs);
+        vis.run_now(&self.ecs);
+        self.ecs.write_resource::<SimClock>().advance(time::TICKS_PER_FRAME);
+        self.ecs.maintain();
+    }
+
+    pub fn step_n(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.step();
+        }
+    }
+}
+
+pub fn create_worker(ecs: &mut World, r_start: u32, c_start: u32) -> Entity {
+    let now = ecs.try_fetch::<SimClock>().map_or(0, |clock| clock.now());
+    let skills = ecs.try_fetch_mut::<RandomNumberGenerator>().map_or_else(Skills::default, |mut rand| Skills::rolled(&mut rand));
+    ecs.create_entity().with(Transform {
+                    r: r_start,
+                    c: c_start,
+                    ch: '@' as u16,
+                    color: rltk::RGB::named(rltk::RED)
+                }).with(
+                    Worker::new()
+                ).with(
+                    Selectable::new()
+                ).with(
+                    Actor::new(2)
+                ).with(
+                    Inventory::new(WORKER_CAPACITY)
+                ).with(
+                    Viewshed::new(WORKER_SIGHT)
+                ).with(
+                    Faction::Player
+                ).with(
+                    Health::new(WORKER_HEALTH)
+                ).with(
+                    CombatStats { attack: 3, defense: 1 }
+                ).with(
+                    Needs::new(now)
+                ).with(
+                    skills
+                ).build()
+}
+
+pub fn create_monster(ecs: &mut World, r: u32, c: u32) {
+    ecs.create_entity().with(Transform {
+                    r,
+                    c,
+                    ch: 'g' as u16,
+                    color: rltk::RGB::named(rltk::GREEN)
+                }).with(
+                    Actor::new(2)
+                ).with(
+                    Faction::Hostile
+                ).with(
+                    Health::new(MONSTER_HEALTH)
+                ).with(
+                    CombatStats { attack: 4, defense: 0 }
+                ).with(
+                    Viewshed::new(MONSTER_SIGHT)
+                ).with(
+                    Monster::new((r, c), MONSTER_LEASH)
+                ).build();
+}
+
+pub fn create_stockpile(ecs: &mut World, r: u32, c: u32) {
+    ecs.create_entity().with(Transform {
+                    r,
+                    c,
+                    ch: '=' as u16,
+                    color: rltk::RGB::named(rltk::BROWN1)
+                }).with(Stockpile).build();
+}
+
+pub fn create_headquarters(ecs: &mut World, r: u32, c: u32) {
+    ecs.create_entity().with(Transform {
+                    r,
+                    c,
+                    ch: 'H' as u16,
+                    color: rltk::RGB::named(rltk::GOLD)
+                }).with(
+                    Selectable::new()
+                ).with(
+                    Headquarters::default()
+                ).build();
+}
+
+pub fn create_deposit(ecs: &mut World, r: u32, c: u32, kind: ResourceKind, remaining: u32) {
+    let (ch, color) = kind.glyph();
+    ecs.create_entity().with(Transform {
+                    r,
+                    c,
+                    ch,
+                    color,
+                }).with(ResourceDeposit {
+                    kind,
+                    remaining,
+                }).build();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WorkerTask;
+    use crate::path::distance;
+    use crate::needs::HUNGRY_AT;
+
+    #[test]
+    fn selected_worker_walks_around_the_wall() {
+        let mut sim = Simulation::new(0);
+        let (r, c) = sim.ecs.fetch::<Map>().spawn_points()[0];
+        *sim.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::ClickSelect(r, c));
+        sim.step();
+        *sim.ecs.write_resource::<MouseEvent>() = MouseEvent(MouseEventT::MoveTo(10, 20));
+        sim.step_n(600);
+
+        let (transforms, selectables) = (sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<Selectable>());
+        let selected: Vec<_> = (&transforms, &selectables).join()
+            .filter(|(_, select)| select.selected)
+            .map(|(trans, _)| (trans.r, trans.c))
+            .collect();
+        assert_eq!(selected, vec![(10, 20)]);
+    }
+
+    fn first_worker(sim: &Simulation) -> Entity {
+        (&sim.ecs.entities(), &sim.ecs.read_storage::<Worker>()).join().next().unwrap().0
+    }
+
+    fn position(sim: &Simulation, entity: Entity) -> (u32, u32) {
+        let trans = sim.ecs.read_storage::<Transform>().get(entity).cloned().unwrap();
+        (trans.r, trans.c)
+    }
+
+    #[test]
+    fn new_move_order_drops_the_old_route() {
+        let mut sim = Simulation::new(0);
+        let worker = first_worker(&sim);
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::MoveTo(79, 49));
+        sim.step_n(20);
+        let before = distance(position(&sim, worker), (0, 0));
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::MoveTo(0, 0));
+        sim.step_n(40);
+        assert!(distance(position(&sim, worker), (0, 0)) < before, "still walking the old route");
+    }
+
+    fn deposit_at_edge(sim: &Simulation, kind: ResourceKind, rightmost: bool) -> (Entity, (u32, u32)) {
+        let (entities, transforms, deposits) = (sim.ecs.entities(), sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<ResourceDeposit>());
+        let found = (&entities, &transforms, &deposits).join()
+            .filter(|(_, _, deposit)| deposit.kind == kind)
+            .map(|(e, trans, _)| (e, (trans.r, trans.c)));
+        if rightmost { found.max_by_key(|(_, at)| at.0) } else { found.min_by_key(|(_, at)| at.0) }.unwrap()
+    }
+
+    #[test]
+    fn new_order_replaces_the_action_under_way() {
+        let mut sim = Simulation::new(0);
+        let worker = first_worker(&sim);
+        // gold off to one side, food off to the other
+        let (gold, _) = deposit_at_edge(&sim, ResourceKind::Gold, false);
+        let (food, food_at) = deposit_at_edge(&sim, ResourceKind::Food, true);
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::Mine(gold));
+        sim.step_n(20);
+        let before = distance(position(&sim, worker), food_at);
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::Mine(food));
+        sim.step_n(20);
+        assert!(distance(position(&sim, worker), food_at) < before, "still on the way to the gold");
+    }
+
+    #[test]
+    fn mined_gold_ends_up_in_the_stockpile() {
+        let mut sim = Simulation::new(0);
+        let worker = first_worker(&sim);
+        // more than fits in one load, so it takes two trips
+        let (gold, _) = deposit_at_edge(&sim, ResourceKind::Gold, true);
+        sim.ecs.write_storage::<ResourceDeposit>().get_mut(gold).unwrap().remaining = WORKER_CAPACITY + 2;
+        let before = sim.ecs.fetch::<Resources>().get(ResourceKind::Gold);
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::Mine(gold));
+        sim.step_n(3000);
+        assert!(!sim.ecs.is_alive(gold), "deposit not mined out");
+        assert_eq!(sim.ecs.read_storage::<Inventory>().get(worker).unwrap().count(ResourceKind::Gold), 0);
+        assert_eq!(sim.ecs.fetch::<Resources>().get(ResourceKind::Gold), before + WORKER_CAPACITY + 2);
+    }
+
+    #[test]
+    fn hunger_cuts_a_long_walk_short() {
+        let mut sim = Simulation::new(0);
+        let worker = first_worker(&sim);
+        let stockpile = {
+            let (transforms, stockpiles) = (sim.ecs.read_storage::<Transform>(), sim.ecs.read_storage::<Stockpile>());
+            (&transforms, &stockpiles).join().map(|(trans, _)| (trans.r, trans.c)).next().unwrap()
+        };
+        sim.ecs.write_storage::<Worker>().get_mut(worker).unwrap().order(WorkerTask::MoveTo(79, 49));
+        sim.ecs.write_storage::<Needs>().get_mut(worker).unwrap().hunger = HUNGRY_AT - 1;
+        for _ in 0..200 {
+            if sim.ecs.read_storage::<Worker>().get(worker).unwrap().task == WorkerTask::Eat {
+                break;
+            }
+            sim.step();
+        }
+        assert!(sim.ecs.read_storage::<Worker>().get(worker).unwrap().task == WorkerTask::Eat, "never got hungry");
+        assert!(sim.ecs.read_storage::<Worker>().get(worker).unwrap().queue.front() == Some(&WorkerTask::MoveTo(79, 49)));
+
+        let before = distance(position(&sim, worker), stockpile);
+        sim.step_n(20);
+        assert!(distance(position(&sim, worker), stockpile) < before, "kept walking away from the food");
+    }
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn too_many_frames_is_an_error() {
+        assert_eq!(parse(&["--frames", "4294967295"]).map(|options| options.frames), Ok(u32::MAX));
+        assert!(parse(&["--frames", "4294967296"]).is_err());
+        assert!(parse(&["--frames", "lots"]).is_err());
+    }
+
+    #[test]
+    fn only_one_map_source() {
+        assert!(parse(&["--load", "save.json"]).is_ok());
+        assert!(parse(&["--caves", "--seed", "3"]).is_ok());
+        for args in [["--load", "save.json", "--generate"], ["--caves", "--load", "save.json"], ["map.txt", "--load", "save.json"], ["--generate", "--caves", "map.txt"]] {
+            assert!(parse(&args).is_err(), "{:?} was accepted", args);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use rltk::RandomNumberGenerator;
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+
+pub const MAX_LEVEL: u32 = 10;
+// level n takes XP_PER_LEVEL * (1 + 2 + .. + n) experience
+const XP_PER_LEVEL: u32 = 10;
+// every level takes this much off the time an action needs, in percent
+const PERCENT_PER_LEVEL: u32 = 5;
+// new workers start with up to this many levels in each skill
+const MAX_STARTING_LEVEL: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SkillKind {
+    Mining,// mining deposits and digging out tiles
+    Hauling,// delivering, fetching and handing over resources
+    Building,
+    Combat,
+}
+
+impl SkillKind {
+    pub const ALL: [SkillKind; 4] = [SkillKind::Mining, SkillKind::Hauling, SkillKind::Building, SkillKind::Combat];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkillKind::Mining => "mining",
+            SkillKind::Hauling => "hauling",
+            SkillKind::Building => "building",
+            SkillKind::Combat => "combat",
+        }
+    }
+}
+
+fn xp_for_level(level: u32) -> u32 {
+    XP_PER_LEVEL * level * (level + 1) / 2
+}
+
+/// Experience of a worker in every skill. Each action of a skill that gets something done is worth a point,
+/// the higher the level the quicker those actions go.
+#[derive(Component, Debug, Clone, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Skills {
+    xp: HashMap<SkillKind, u32>,
+}
+
+impl Skills {
+    /// Somewhere between a greenhorn and a few levels in everything.
+    pub fn rolled(rand: &mut RandomNumberGenerator) -> Self {
+        let xp = SkillKind::ALL.iter()
+            .map(|&kind| (kind, xp_for_level(rand.range(0, MAX_STARTING_LEVEL as i32 + 1) as u32)))
+            .collect();
+        Self { xp }
+    }
+
+    pub fn xp(&self, kind: SkillKind) -> u32 {
+        self.xp.get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn level(&self, kind: SkillKind) -> u32 {
+        let xp = self.xp(kind);
+        (0..MAX_LEVEL).take_while(|&level| xp >= xp_for_level(level + 1)).count() as u32
+    }
+
+    /// Experience still missing for the next level, `None` at the top.
+    pub fn xp_to_next(&self, kind: SkillKind) -> Option<u32> {
+        let level = self.level(kind);
+        (level < MAX_LEVEL).then(|| xp_for_level(level + 1) - self.xp(kind))
+    }
+
+    pub fn gain(&mut self, kind: SkillKind) {
+        *self.xp.entry(kind).or_insert(0) += 1;
+    }
+
+    /// How long an action of `kind` takes, in percent of the unskilled time.
+    pub fn time_percent(&self, kind: SkillKind) -> u64 {
+        (100 - self.level(kind) * PERCENT_PER_LEVEL) as u64
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use rltk::RGB;
+use serde::{Serialize, Deserialize};
+
+use super::map::MapLoadError;
+use super::resource::ResourceKind;
+
+/// Movement cost of plain floor, `TileType::cost` is in percent of this.
+pub const BASE_COST: u32 = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileType {
+    pub name: String,
+    pub ch: u16,
+    pub fg: RGB,
+    pub bg: RGB,
+    pub walkable: bool,
+    pub opaque: bool,
+    pub cost: u32,
+    pub minable: bool,
+    pub hardness: u32,
+    pub drops: Option<(ResourceKind, u32)>,// left behind when dug out
+}
+
+/// Index into the `TileTypes` a map was built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileId(u16);
+
+/// A line that means something in a data file, see `data/tiles.txt` for the layout.
+pub(crate) enum DataLine<'a> {
+    Section(&'a str),// [name]
+    Entry(&'a str, &'a str),// key = value
+}
+
+/// The sections and entries of a data file with their line numbers, blank lines and comments left out.
+pub(crate) fn data_lines(text: &str) -> impl Iterator<Item = Result<(usize, DataLine<'_>), MapLoadError>> {
+    text.lines().enumerate().filter_map(|(i, line)| {
+        let line_no = i + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return None;
+        }
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            return Some(Ok((line_no, DataLine::Section(trimmed[1..trimmed.len() - 1].trim()))));
+        }
+        Some(match trimmed.split_once('=') {
+            Some((key, value)) => Ok((line_no, DataLine::Entry(key.trim(), value.trim()))),
+            None => Err(MapLoadError::BadLine { line: line_no, reason: "expected 'key = value'".to_string() }),
+        })
+    })
+}
+
+/// A single character, quoted when it is a space: `x` or `' '`.
+pub(crate) fn parse_glyph(value: &str) -> Result<u16, String> {
+    let unquoted = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')).unwrap_or(value);
+    let mut chars = unquoted.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => Ok(rltk::to_cp437(ch)),
+        _ => Err(format!("glyph '{}' must be a single character", value)),
+    }
+}
+
+/// A resource and an amount of it, like `stone 2`. `key` only goes into the error.
+pub(crate) fn parse_amount(key: &str, value: &str) -> Result<(ResourceKind, u32), String> {
+    value.split_once(' ')
+        .and_then(|(kind, amount)| Some((ResourceKind::from_name(kind.trim())?, amount.trim().parse().ok()?)))
+        .ok_or_else(|| format!("{} must look like 'stone 2', got '{}'", key, value))
+}
+
+/// All the kinds of terrain, see `data/tiles.txt`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileTypes {
+    types: Vec<TileType>,
+    floor: TileId,
+    wall: TileId,
+}
+
+impl TileTypes {
+    pub fn builtin() -> Self {
+        Self::parse(include_str!("../data/tiles.txt")).expect("built in tile types are broken")
+    }
+
+    /// Reads `[name]` sections of `key = value` lines, the same layout as map files.
+    pub fn parse(text: &str) -> Result<Self, MapLoadError> {
+        let mut types: Vec<TileType> = vec![];
+        let mut seen: HashMap<String, usize> = HashMap::new();
+
+        for line in data_lines(text) {
+            let (line_no, line) = line?;
+            let bad_line = |reason: String| MapLoadError::BadLine { line: line_no, reason };
+            let (key, value) = match line {
+                DataLine::Entry(key, value) => (key, value),
+                DataLine::Section(name) => {
+                    let name = name.to_string();
+                    if seen.insert(name.clone(), line_no).is_some() {
+                        return Err(bad_line(format!("tile type [{}] is defined twice", name)));
+                    }
+                    types.push(TileType {
+                        name,
+                        ch: '?' as u16,
+                        fg: RGB::named(rltk::WHITE),
+                        bg: RGB::named(rltk::BLACK),
+                        walkable: false,
+                        opaque: false,
+                        cost: BASE_COST,
+                        minable: false,
+                        hardness: 1,
+                        drops: None,
+                    });
+                    continue;
+                },
+            };
+            let tile = types.last_mut().ok_or_else(|| bad_line("line outside of any tile type".to_string()))?;
+            let flag = |value: &str| match value {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                _ => Err(bad_line(format!("{} must be yes or no, got '{}'", key, value))),
+            };
+            let number = |value: &str| value.parse::<u32>().map_err(|_| bad_line(format!("{} must be a number, got '{}'", key, value)));
+            let color = |value: &str| RGB::from_hex(value).map_err(|_| bad_line(format!("{} must look like #rrggbb, got '{}'", key, value)));
+            match key {
+                "glyph" => tile.ch = parse_glyph(value).map_err(bad_line)?,
+                "fg" => tile.fg = color(value)?,
+                "bg" => tile.bg = color(value)?,
+                "walkable" => tile.walkable = flag(value)?,
+                "opaque" => tile.opaque = flag(value)?,
+                "cost" => tile.cost = number(value)?.max(1),
+                "minable" => tile.minable = flag(value)?,
+                "hardness" => tile.hardness = number(value)?.max(1),
+                "drops" => tile.drops = Some(parse_amount(key, value).map_err(bad_line)?),
+                _ => return Err(bad_line(format!("unknown tile key '{}'", key))),
+            }
+        }
+
+        let find = |name: &'static str| types.iter().position(|t| t.name == name)
+            .map(|i| TileId(i as u16))
+            .ok_or(MapLoadError::MissingTileType(name));
+        let (floor, wall) = (find("floor")?, find("wall")?);
+        Ok(Self { types, floor, wall })
+    }
+
+    pub fn get(&self, id: TileId) -> &TileType {
+        &self.types[id.0 as usize]
+    }
+
+    pub fn id(&self, name: &str) -> Option<TileId> {
+        self.types.iter().position(|t| t.name == name).map(|i| TileId(i as u16))
+    }
+
+    pub fn floor(&self) -> TileId {
+        self.floor
+    }
+
+    pub fn wall(&self) -> TileId {
+        self.wall
+    }
+
+    /// The cheapest walkable terrain, keeps path estimates from overshooting.
+    pub fn min_cost(&self) -> u32 {
+        self.types.iter().filter(|t| t.walkable).map(|t| t.cost).min().unwrap_or(BASE_COST)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_types_have_floor_and_wall() {
+        let types = TileTypes::builtin();
+        assert_eq!(types.get(types.floor()).name, "floor");
+        assert_eq!(types.get(types.wall()).name, "wall");
+    }
+
+    #[test]
+    fn missing_wall_is_its_own_error() {
+        let err = TileTypes::parse("[floor]\nwalkable = yes\n").err().expect("types without a wall should not parse");
+        assert!(matches!(err, MapLoadError::MissingTileType("wall")), "{}", err);
+    }
+
+    #[test]
+    fn glyphs_and_amounts() {
+        assert_eq!(parse_glyph("' '"), Ok(rltk::to_cp437(' ')));
+        assert_eq!(parse_glyph("#"), Ok(rltk::to_cp437('#')));
+        assert!(parse_glyph("ab").is_err());
+        assert_eq!(parse_amount("cost", "stone 2"), Ok((ResourceKind::Stone, 2)));
+        assert!(parse_amount("cost", "stone").is_err());
+        assert!(parse_amount("cost", "cheese 2").is_err());
+    }
+}
@@ -1,37 +1,106 @@
+use serde::{Serialize, Deserialize};
 use specs::{Component, VecStorage};
+use specs::saveload::{ConvertSaveload, Marker};
+use specs::error::NoError;
 
 use specs::System;
-use specs::{ReadExpect, WriteStorage};
+use specs::{Read, Write, ReadExpect, WriteExpect, ReadStorage, WriteStorage, Entity, Entities};
 use specs::Join;
+use std::collections::{HashMap, HashSet};
 
 use super::{Transform, MoveMap};
+use super::map::Map;
+use super::job::{JobBoard, JobKind};
+use super::path;
+use super::tile::BASE_COST;
+use super::resource::{ResourceKind, ResourceDeposit, Inventory, Stockpile, Resources};
+use super::build::{Blueprint, BuildingCatalog};
+use super::combat::{self, Health, CombatStats, Faction};
+use super::needs::{Needs, Bed};
+use super::skill::{Skills, SkillKind};
 
-use std::time as time;
+pub const TICKS_PER_SECOND: u64 = 60;
+pub const TICKS_PER_FRAME: u64 = 2;
+
+const MINE_TIME_FACTOR: u64 = 3;
+const MINE_YIELD: u32 = 1;
+// a swing takes this many steps worth of time, so faster units also hit more often
+const ATTACK_TIME_FACTOR: u64 = 2;
+// digging out a tile of hardness 1, harder tiles take that many times longer
+const DIG_TIME_FACTOR: u64 = 4;
+const EAT_TIME_FACTOR: u64 = 3;
+const SLEEP_TIME_FACTOR: u64 = 10;
+
+// a blocked walker checks its next tile once a frame, tries a way around the jam
+// every REROUTE_CHECKS and drops the order after GIVE_UP_CHECKS
+const REROUTE_CHECKS: u32 = 15;
+const GIVE_UP_CHECKS: u32 = 150;
+
+/// Simulation time, counted in ticks. Only advances when the simulation is stepped,
+/// so the same inputs always play out the same way regardless of frame timing.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SimClock {
+    tick: u64,
+}
+
+impl SimClock {
+    pub fn now(&self) -> u64 {
+        self.tick
+    }
+    pub fn advance(&mut self, ticks: u64) {
+        self.tick += ticks;
+    }
+}
 
 #[derive(Component)]
 #[storage(VecStorage)]
 pub struct Actor {
     speed: i32,
+    slowed: bool,// everything takes twice as long
     action: Option<Action>,
+    failure: Option<ActionFailure>,
 }
 
 impl Actor {
     pub fn new(speed: i32) -> Self{
         Self {
-            speed: speed,
+            speed,
+            slowed: false,
             action: None,
+            failure: None,
         }
     }
 
-    pub fn get_execution_time(&self) -> time::Duration { time::Duration::from_millis((1000 / self.speed).try_into().unwrap()) }
-    pub fn new_action(&mut self, action: ActionType) -> bool {
+    pub fn get_execution_time(&self) -> u64 {
+        let time = TICKS_PER_SECOND / self.speed as u64;
+        if self.slowed { time * 2 } else { time }
+    }
+    /// Takes effect from the next action on.
+    pub fn set_slowed(&mut self, slowed: bool) {
+        self.slowed = slowed;
+    }
+    pub fn is_slowed(&self) -> bool {
+        self.slowed
+    }
+    fn execution_time_for(&self, action: &ActionType) -> u64 {
+        match action {
+            ActionType::Mine(_) => self.get_execution_time() * MINE_TIME_FACTOR,
+            ActionType::Attack(_) => self.get_execution_time() * ATTACK_TIME_FACTOR,
+            ActionType::Eat(_) => self.get_execution_time() * EAT_TIME_FACTOR,
+            ActionType::Sleep(_) => self.get_execution_time() * SLEEP_TIME_FACTOR,
+            _ => self.get_execution_time(),
+        }
+    }
+    pub fn new_action(&mut self, action: ActionType, now: u64) -> bool {
         match self.action {
             Some(_) => false,
             None => {
                 self.action = Some(Action{
-                    start_time: time::Instant::now(),
-                    execution_time: self.get_execution_time(),
+                    start_time: now,
+                    execution_time: self.execution_time_for(&action),
                     t: action,
+                    path: None,
+                    blocked: 0,
                 });
                 true
             },
@@ -41,80 +110,652 @@ impl Actor {
     pub fn is_busy(&self) -> bool {
         self.action.is_some()
     }
+
+    /// Drops whatever is under way, route included. Nothing counts as failed.
+    pub fn cancel(&mut self) {
+        self.action = None;
+    }
+
+    /// Where a `MoveTo` under way is headed.
+    pub fn destination(&self) -> Option<(u32, u32)> {
+        match self.action.as_ref()?.t {
+            ActionType::MoveTo(r, c) => Some((r, c)),
+            _ => None,
+        }
+    }
+
+    /// Not doing anything that matters, it's fine to shove this one aside.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.action, None | Some(Action { t: ActionType::Move(..), .. }))
+    }
+
+    /// The tile this actor is about to step onto from `pos`, if it is walking.
+    pub fn next_tile(&self, pos: (u32, u32)) -> Option<(u32, u32)> {
+        let action = self.action.as_ref()?;
+        match action.t {
+            ActionType::Move(dr, dc) => {
+                let (r, c) = (pos.0 as i32 + dr, pos.1 as i32 + dc);
+                if r < 0 || c < 0 { None } else { Some((r as u32, c as u32)) }
+            },
+            ActionType::MoveTo(..) => action.path.as_ref()?.last().copied(),
+            _ => None,
+        }
+    }
+
+    /// Why the last action was dropped, if it failed, without clearing it.
+    pub fn failure(&self) -> Option<ActionFailure> {
+        self.failure
+    }
+
+    /// Why the last action was dropped, if it failed. Cleared on read.
+    pub fn take_failure(&mut self) -> Option<ActionFailure> {
+        self.failure.take()
+    }
+
+    fn fail(&mut self, failure: ActionFailure) {
+        self.action = None;
+        self.failure = Some(failure);
+    }
 }
 
 struct Action {
-    start_time: time::Instant,
-    execution_time: time::Duration,
+    start_time: u64,// ticks
+    execution_time: u64,
     t: ActionType,
+    path: Option<Vec<(u32, u32)>>,// reversed, next step is last
+    blocked: u32,// checks in a row the next step was taken
 }
 
+#[derive(Clone, Copy)]
 pub enum ActionType {
     Move(i32, i32),
     MoveTo(u32, u32),
+    Mine(Entity),
+    Deliver(Entity),
+    Dig(u32, u32),
+    Withdraw(Entity, ResourceKind, u32),// from a stockpile
+    Supply(Entity),// hand over what a blueprint still needs
+    Build(Entity),
+    Attack(Entity),// something next to the actor
+    Eat(Entity),// food from a stockpile
+    Sleep(Option<Entity>),// next to a bed, or wherever it stands
 }
 
-fn add( u: u32, i: i32) -> u32{
-    let abs_i: u32 = i.abs().try_into().unwrap();
-    match i {
-        _ if i < 0 => u - abs_i,
-        _ if i > 0 => u + abs_i,
-        _ => u,
+impl ActionType {
+    /// The skill that gets this done quicker and learns from doing it.
+    pub fn skill(&self) -> Option<SkillKind> {
+        match self {
+            ActionType::Mine(_) | ActionType::Dig(..) => Some(SkillKind::Mining),
+            ActionType::Deliver(_) | ActionType::Withdraw(..) | ActionType::Supply(_) => Some(SkillKind::Hauling),
+            ActionType::Build(_) => Some(SkillKind::Building),
+            ActionType::Attack(_) => Some(SkillKind::Combat),
+            _ => None,
+        }
     }
 }
-fn normalize(i: i32) -> i32 {
-    match i {
-        _ if i < 0 => -1,
-        _ if i > 0 => 1,
-        _ => 0,
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActionFailure {
+    Unreachable(u32, u32),
+    OutOfReach(u32, u32),// got pushed away from what it was working on
+}
+
+// Saved form of an Actor, entities are replaced by their save markers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ActorData<M> {
+    speed: i32,
+    slowed: bool,
+    action: Option<ActionData<M>>,
+    failure: Option<ActionFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ActionData<M> {
+    start_time: u64,
+    execution_time: u64,
+    t: ActionTypeData<M>,
+    path: Option<Vec<(u32, u32)>>,
+    blocked: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ActionTypeData<M> {
+    Move(i32, i32),
+    MoveTo(u32, u32),
+    Mine(M),
+    Deliver(M),
+    Dig(u32, u32),
+    Withdraw(M, ResourceKind, u32),
+    Supply(M),
+    Build(M),
+    Attack(M),
+    Eat(M),
+    Sleep(Option<M>),
+}
+
+impl<M: Marker> ConvertSaveload<M> for Actor {
+    type Data = ActorData<M>;
+    type Error = NoError;
+
+    fn convert_into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+    where
+        F: FnMut(Entity) -> Option<M>,
+    {
+        // an action on an entity that is already gone is dropped
+        let action = self.action.as_ref().and_then(|action| {
+            let t = match action.t {
+                ActionType::Move(dr, dc) => ActionTypeData::Move(dr, dc),
+                ActionType::MoveTo(r, c) => ActionTypeData::MoveTo(r, c),
+                ActionType::Mine(target) => ActionTypeData::Mine(ids(target)?),
+                ActionType::Deliver(target) => ActionTypeData::Deliver(ids(target)?),
+                ActionType::Dig(r, c) => ActionTypeData::Dig(r, c),
+                ActionType::Withdraw(target, kind, amount) => ActionTypeData::Withdraw(ids(target)?, kind, amount),
+                ActionType::Supply(target) => ActionTypeData::Supply(ids(target)?),
+                ActionType::Build(target) => ActionTypeData::Build(ids(target)?),
+                ActionType::Attack(target) => ActionTypeData::Attack(ids(target)?),
+                ActionType::Eat(target) => ActionTypeData::Eat(ids(target)?),
+                ActionType::Sleep(bed) => ActionTypeData::Sleep(bed.and_then(&mut ids)),
+            };
+            Some(ActionData {
+                start_time: action.start_time,
+                execution_time: action.execution_time,
+                t,
+                path: action.path.clone(),
+                blocked: action.blocked,
+            })
+        });
+        Ok(ActorData { speed: self.speed, slowed: self.slowed, action, failure: self.failure })
     }
+
+    fn convert_from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+    where
+        F: FnMut(M) -> Option<Entity>,
+    {
+        let action = data.action.and_then(|action| {
+            let t = match action.t {
+                ActionTypeData::Move(dr, dc) => ActionType::Move(dr, dc),
+                ActionTypeData::MoveTo(r, c) => ActionType::MoveTo(r, c),
+                ActionTypeData::Mine(target) => ActionType::Mine(ids(target)?),
+                ActionTypeData::Deliver(target) => ActionType::Deliver(ids(target)?),
+                ActionTypeData::Dig(r, c) => ActionType::Dig(r, c),
+                ActionTypeData::Withdraw(target, kind, amount) => ActionType::Withdraw(ids(target)?, kind, amount),
+                ActionTypeData::Supply(target) => ActionType::Supply(ids(target)?),
+                ActionTypeData::Build(target) => ActionType::Build(ids(target)?),
+                ActionTypeData::Attack(target) => ActionType::Attack(ids(target)?),
+                ActionTypeData::Eat(target) => ActionType::Eat(ids(target)?),
+                ActionTypeData::Sleep(bed) => ActionType::Sleep(bed.and_then(&mut ids)),
+            };
+            Some(Action {
+                start_time: action.start_time,
+                execution_time: action.execution_time,
+                t,
+                path: action.path,
+                blocked: action.blocked,
+            })
+        });
+        Ok(Actor { speed: data.speed, slowed: data.slowed, action, failure: data.failure })
+    }
+}
+
+// how long a step onto terrain of `cost` takes for an actor with `base` step time
+fn step_time(base: u64, cost: u32) -> u64 {
+    base * cost as u64 / BASE_COST as u64
+}
+
+// when an action is done, with `percent` of its time left after skills
+fn done_at(action: &Action, percent: u64) -> u64 {
+    action.start_time + action.execution_time * percent / 100
+}
+
+// Stretches an action that turned out to take longer than first timed, true while it isn't done yet.
+fn wait_until_done(action: &mut Action, execution_time: u64, percent: u64, now: u64) -> bool {
+    action.execution_time = action.execution_time.max(execution_time);
+    now < done_at(action, percent)
+}
+
+// a step onto slow terrain, no skill for walking
+fn wait_for_terrain(action: &mut Action, base: u64, cost: u32, now: u64) -> bool {
+    wait_until_done(action, step_time(base, cost), 100, now)
+}
+
+fn find_path(mmap: &MoveMap, transform: &Transform, dest: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    path::a_star(mmap, (transform.r, transform.c), dest).map(|mut path| {
+        path.reverse();
+        path
+    })
+}
+
+fn find_path_around_units(mmap: &MoveMap, transform: &Transform, dest: (u32, u32)) -> Option<Vec<(u32, u32)>> {
+    path::a_star_around_units(mmap, (transform.r, transform.c), dest).map(|mut path| {
+        path.reverse();
+        path
+    })
 }
 
 pub struct TimeManager;
 impl<'a> System<'a> for TimeManager{
     type SystemData = (
-            ReadExpect<'a, MoveMap>,
+            Entities<'a>,
+            Read<'a, SimClock>,
+            Write<'a, Resources>,
+            WriteExpect<'a, MoveMap>,
+            WriteExpect<'a, Map>,
+            Write<'a, JobBoard>,
+            ReadExpect<'a, BuildingCatalog>,
             WriteStorage<'a, Actor>,
             WriteStorage<'a, Transform>,
+            WriteStorage<'a, ResourceDeposit>,
+            WriteStorage<'a, Inventory>,
+            ReadStorage<'a, Stockpile>,
+            WriteStorage<'a, Blueprint>,
+            WriteStorage<'a, Health>,
+            ReadStorage<'a, CombatStats>,
+            ReadStorage<'a, Faction>,
+            WriteStorage<'a, Needs>,
+            ReadStorage<'a, Bed>,
+            WriteStorage<'a, Skills>,
         );
 
     fn run(&mut self, data: Self::SystemData){
-        let (mmap, mut actors, mut transforms) = data;
-        let now = time::Instant::now();
-
-        for (actor, transform) in (&mut actors, &mut transforms).join() {
-            if let Some(action) = &mut actor.action {
-                if now > ( action.start_time + action.execution_time) {
-                    match action.t {
-                        ActionType::Move(dr, dc) => {
-                            let (new_r, new_c) = (add(transform.r, dr), add(transform.c, dc));
-                            if mmap.map[new_r as usize][new_c as usize] {
-                                transform.r = new_r;
-                                transform.c = new_c;
+        let (entities, clock, mut resources, mut mmap, mut map, mut jobs, catalog, mut actors, mut transforms, mut deposits, mut inventories, stockpiles, mut blueprints, mut healths, combat_stats, factions, mut needs, beds, mut skills) = data;
+        let now = clock.now();
+        let idle: HashSet<Entity> = (&entities, &actors).join()
+            .filter(|(_, actor)| actor.is_idle())
+            .map(|(entity, _)| entity)
+            .collect();
+        let mut swaps = vec![];// (idle unit, where it got pushed to)
+        let mut drops = vec![];// (tile, what a dug out tile left behind)
+        let mut attacks = vec![];// (attacker, where it stands, target), resolved once everyone moved
+        // deposits and stockpiles stay put, worked on from the next tile over
+        let places: HashMap<Entity, (u32, u32)> = (&entities, &transforms).join()
+            .filter(|(entity, _)| deposits.contains(*entity) || stockpiles.contains(*entity))
+            .map(|(entity, trans)| (entity, (trans.r, trans.c)))
+            .collect();
+        let out_of_reach = |target: Entity, pos: (u32, u32)| places.get(&target).copied().filter(|&at| !path::is_adjacent(pos, at));
+
+        for (entity, actor, transform, inventory, needs, skills) in (&entities, &mut actors, &mut transforms, (&mut inventories).maybe(), (&mut needs).maybe(), (&mut skills).maybe()).join() {
+            let base = actor.get_execution_time();
+            let skill = actor.action.as_ref().and_then(|action| action.t.skill());
+            let percent = match (skill, skills.as_deref()) {
+                (Some(kind), Some(skills)) => skills.time_percent(kind),
+                _ => 100,
+            };
+            let action = match &mut actor.action {
+                Some(action) if now >= done_at(action, percent) => action,
+                _ => continue,
+            };
+            let mut learned = false;// only actions that got something done count towards the skill
+            match action.t {
+                ActionType::Move(dr, dc) => {
+                    let (new_r, new_c) = (transform.r as i32 + dr, transform.c as i32 + dc);
+                    if wait_for_terrain(action, base, mmap.move_cost(new_r, new_c), now) {
+                        continue;
+                    }
+                    if mmap.is_free_for(entity, new_r, new_c) {
+                        mmap.move_unit(entity, (transform.r, transform.c), (new_r as u32, new_c as u32));
+                        transform.r = new_r as u32;
+                        transform.c = new_c as u32;
+                    }
+                    actor.action = None;
+                },
+                ActionType::MoveTo(dr, dc) => {
+                    if action.path.is_none() {
+                        action.path = find_path(&mmap, transform, (dr, dc));
+                        // the first step was timed before the route was known
+                        if let Some(&(first_r, first_c)) = action.path.as_ref().and_then(|path| path.last()) {
+                            if wait_for_terrain(action, base, mmap.move_cost(first_r as i32, first_c as i32), now) {
+                                continue;
                             }
+                        }
+                    }
+                    let next = match &mut action.path {
+                        Some(path) => path.last().copied(),
+                        None => {
+                            actor.fail(ActionFailure::Unreachable(dr, dc));
+                            continue;
+                        },
+                    };
+                    let (next_r, next_c) = match next {
+                        Some(next) => next,
+                        None => {
                             actor.action = None;
+                            continue;
                         },
-                        ActionType::MoveTo(dr, dc) => {
-                            let (diff_r, diff_c) = (dr as i32 - transform.r as i32, dc as i32 - transform.c as i32);
-                            let (change_r, change_c) = ( normalize(diff_r), normalize(diff_c));
-                            let (next_r, next_c) = (add(transform.r, change_r), add(transform.c, change_c));
-
-                            if mmap.map[next_r as usize][next_c as usize] {
-                                transform.r = next_r;
-                                transform.c = next_c;
-                                if next_r == dr || next_c == dc {
-                                    actor.action = None;
-                                } else {
-                                    action.start_time = now;
-                                }
-                            } else {
-                                actor.action = None;
+                    };
+
+                    if !mmap.is_walkable(next_r as i32, next_c as i32) {
+                        // something got built in the way, look for another route
+                        match find_path(&mmap, transform, (dr, dc)) {
+                            Some(path) => {
+                                action.execution_time = path.last().map_or(base, |&(r, c)| step_time(base, mmap.move_cost(r as i32, c as i32)));
+                                action.path = Some(path);
+                                action.start_time = now;
+                            },
+                            None => actor.fail(ActionFailure::Unreachable(dr, dc)),
+                        }
+                        continue;
+                    }
+
+                    let pos = (transform.r, transform.c);
+                    let next = (next_r, next_c);
+                    // idle units of the same side standing in the way trade places, unless someone else already claimed the tile
+                    let swap_with = match mmap.unit_at(next_r as i32, next_c as i32) {
+                        Some(other) if idle.contains(&other) && factions.get(other) == factions.get(entity)
+                            && mmap.reserved_by(next_r as i32, next_c as i32).is_none_or(|owner| owner == entity || owner == other) => Some(other),
+                        _ => None,
+                    };
+                    if swap_with.is_none() && !mmap.is_free_for(entity, next_r as i32, next_c as i32) {
+                        let last_step = action.path.as_ref().is_some_and(|path| path.len() == 1);
+                        if last_step && mmap.unit_at(next_r as i32, next_c as i32).is_some() {
+                            // someone busy is standing on the destination, this is as close as it gets
+                            actor.action = None;
+                            continue;
+                        }
+                        action.blocked += 1;
+                        if action.blocked >= GIVE_UP_CHECKS {
+                            actor.fail(ActionFailure::Unreachable(dr, dc));
+                        } else if action.blocked % REROUTE_CHECKS == 0 {
+                            if let Some(path) = find_path_around_units(&mmap, transform, (dr, dc)) {
+                                action.path = Some(path);
                             }
+                        }
+                        continue;
+                    }
 
+                    if let Some(other) = swap_with {
+                        mmap.move_unit(other, next, pos);
+                        swaps.push((other, pos));
+                    }
+                    mmap.move_unit(entity, pos, next);
+                    transform.r = next_r;
+                    transform.c = next_c;
+                    action.blocked = 0;
+                    let path = action.path.as_mut().unwrap();
+                    path.pop();
+                    match path.last() {
+                        Some(&following) => {
+                            mmap.reserve(entity, following);
+                            action.start_time = now;
+                            action.execution_time = step_time(base, mmap.move_cost(following.0 as i32, following.1 as i32));
                         },
+                        None => actor.action = None,
+                    }
+                },
+                ActionType::Mine(target) => {
+                    if let Some((r, c)) = out_of_reach(target, (transform.r, transform.c)) {
+                        actor.fail(ActionFailure::OutOfReach(r, c));
+                        continue;
+                    }
+                    if let (Some(deposit), Some(inventory)) = (deposits.get_mut(target), inventory) {
+                        let amount = inventory.add(deposit.kind, MINE_YIELD.min(deposit.remaining));
+                        deposit.remaining -= amount;
+                        learned = amount > 0;
+                        if deposit.remaining == 0 {
+                            entities.delete(target).expect("deleting depleted deposit");
+                        }
+                    }
+                    actor.action = None;
+                },
+                ActionType::Deliver(stockpile) => {
+                    if let Some((r, c)) = out_of_reach(stockpile, (transform.r, transform.c)) {
+                        actor.fail(ActionFailure::OutOfReach(r, c));
+                        continue;
+                    }
+                    if let (true, Some(inventory)) = (stockpiles.contains(stockpile), inventory) {
+                        for (kind, amount) in inventory.take_all() {
+                            resources.add(kind, amount);
+                            learned |= amount > 0;
+                        }
+                    }
+                    actor.action = None;
+                },
+                ActionType::Withdraw(stockpile, kind, amount) => {
+                    if let Some((r, c)) = out_of_reach(stockpile, (transform.r, transform.c)) {
+                        actor.fail(ActionFailure::OutOfReach(r, c));
+                        continue;
+                    }
+                    if let (true, Some(inventory)) = (stockpiles.contains(stockpile), inventory) {
+                        let taken = resources.take(kind, amount.min(inventory.space()));
+                        learned = inventory.add(kind, taken) > 0;
+                    }
+                    actor.action = None;
+                },
+                ActionType::Supply(site) => {
+                    if let (Some(blueprint), Some(inventory)) = (blueprints.get_mut(site), inventory) {
+                        for (kind, _) in blueprint.missing(&catalog) {
+                            let used = blueprint.supply(&catalog, kind, inventory.count(kind));
+                            inventory.take(kind, used);
+                            learned |= used > 0;
+                        }
+                    }
+                    actor.action = None;
+                },
+                ActionType::Build(site) => {
+                    if let Some(blueprint) = blueprints.get_mut(site) {
+                        if blueprint.is_supplied(&catalog) {
+                            blueprint.progress += 1;
+                            learned = true;
+                        }
+                    }
+                    actor.action = None;
+                },
+                ActionType::Attack(target) => {
+                    // the dead don't swing back
+                    if !healths.get(entity).is_some_and(|health| health.is_dead()) {
+                        attacks.push((entity, (transform.r, transform.c), target));
+                    }
+                    actor.action = None;
+                },
+                ActionType::Eat(stockpile) => {
+                    if let Some((r, c)) = out_of_reach(stockpile, (transform.r, transform.c)) {
+                        actor.fail(ActionFailure::OutOfReach(r, c));
+                        continue;
                     }
+                    if let (true, Some(needs)) = (stockpiles.contains(stockpile), needs) {
+                        if resources.take(ResourceKind::Food, 1) > 0 {
+                            needs.eat();
+                        }
+                    }
+                    actor.action = None;
+                },
+                ActionType::Sleep(bed) => {
+                    if let Some(needs) = needs {
+                        needs.rest(bed.is_some_and(|bed| beds.contains(bed)));
+                    }
+                    actor.action = None;
+                },
+                ActionType::Dig(r, c) => {
+                    let tile = map.at(r, c);
+                    let adjacent = path::is_adjacent((transform.r, transform.c), (r, c));
+                    if tile.minable && adjacent {
+                        if wait_until_done(action, base * DIG_TIME_FACTOR * tile.hardness as u64, percent, now) {
+                            continue;
+                        }
+                        learned = true;
+                        // the digger keeps what it can carry, the rest is left on the new floor
+                        if let Some((kind, amount)) = map.dig_out(r, c) {
+                            let kept = inventory.map_or(0, |inventory| inventory.add(kind, amount));
+                            if kept < amount {
+                                drops.push(((r, c), (kind, amount - kept)));
+                            }
+                        }
+                    }
+                    actor.action = None;
+                },
+            }
+            // attacks only count once they land, that is sorted out after everyone moved
+            if let (true, Some(kind), Some(skills)) = (learned, skill, skills) {
+                skills.gain(kind);
+            }
+        }
+
+        for (other, (r, c)) in swaps {
+            if let Some(transform) = transforms.get_mut(other) {
+                transform.r = r;
+                transform.c = c;
+            }
+        }
+
+        for (attacker, pos, target) in attacks {
+            let in_reach = transforms.get(target).is_some_and(|trans| path::is_adjacent(pos, (trans.r, trans.c)));
+            if let (true, Some(stats), Some(health)) = (in_reach, combat_stats.get(attacker), healths.get_mut(target)) {
+                health.hp -= combat::damage(stats, combat_stats.get(target));
+                if let Some(skills) = skills.get_mut(attacker) {
+                    skills.gain(SkillKind::Combat);
                 }
             }
         }
+
+        for ((r, c), (kind, amount)) in drops {
+            let (ch, color) = kind.glyph();
+            let pile = entities.build_entity()
+                .with(Transform { r, c, ch, color }, &mut transforms)
+                .with(ResourceDeposit { kind, remaining: amount }, &mut deposits)
+                .build();
+            jobs.post(JobKind::Haul(pile));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{WorldExt, Join};
+
+    use super::*;
+    use crate::{Transform, Worker, WorkerTask};
+    use crate::sim::Simulation;
+    use crate::resource::{Inventory, Stockpile};
+
+    fn positions(sim: &Simulation) -> Vec<(u32, u32)> {
+        sim.ecs.read_storage::<Transform>().join().map(|trans| (trans.r, trans.c)).collect()
+    }
+
+    #[test]
+    fn same_seed_plays_out_the_same() {
+        let (mut a, mut b) = (Simulation::new(9), Simulation::new(9));
+        a.step_n(500);
+        b.step_n(500);
+        assert_eq!(positions(&a), positions(&b));
+    }
+
+    // has the first worker hand everything it carries to a stockpile, returns the hauling experience after
+    fn deliver(sim: &mut Simulation) -> u32 {
+        let (worker, stockpile) = {
+            let entities = sim.ecs.entities();
+            let worker = (&entities, &sim.ecs.read_storage::<Skills>()).join().next().unwrap().0;
+            let stockpile = (&entities, &sim.ecs.read_storage::<Stockpile>()).join().next().unwrap().0;
+            (worker, stockpile)
+        };
+        stand_next_to(sim, worker, stockpile);
+        let now = sim.ecs.fetch::<SimClock>().now();
+        assert!(sim.ecs.write_storage::<Actor>().get_mut(worker).unwrap().new_action(ActionType::Deliver(stockpile), now));
+        while sim.ecs.read_storage::<Actor>().get(worker).unwrap().is_busy() {
+            sim.step();
+        }
+        sim.ecs.read_storage::<Skills>().get(worker).unwrap().xp(SkillKind::Hauling)
+    }
+
+    fn stand_next_to(sim: &mut Simulation, entity: Entity, target: Entity) {
+        let mut transforms = sim.ecs.write_storage::<Transform>();
+        let at = transforms.get(target).cloned().unwrap();
+        let trans = transforms.get_mut(entity).unwrap();
+        trans.r = at.r;
+        trans.c = at.c - 1;
+    }
+
+    #[test]
+    fn only_actions_that_do_something_teach() {
+        let mut sim = Simulation::new(3);
+        let start = deliver(&mut sim);
+        assert_eq!(deliver(&mut sim), start, "an empty delivery taught something");
+
+        let worker = (&sim.ecs.entities(), &sim.ecs.read_storage::<Skills>()).join().next().unwrap().0;
+        sim.ecs.write_storage::<Inventory>().get_mut(worker).unwrap().add(ResourceKind::Gold, 3);
+        assert_eq!(deliver(&mut sim), start + 1);
+    }
+
+    #[test]
+    fn delivery_from_afar_fails() {
+        let mut sim = Simulation::new(3);
+        let (worker, stockpile) = {
+            let entities = sim.ecs.entities();
+            let worker = (&entities, &sim.ecs.read_storage::<Worker>()).join().next().unwrap().0;
+            let stockpile = (&entities, &sim.ecs.read_storage::<Stockpile>()).join().next().unwrap().0;
+            (worker, stockpile)
+        };
+        let at = sim.ecs.read_storage::<Transform>().get(stockpile).cloned().unwrap();
+        let before = sim.ecs.fetch::<Resources>().get(ResourceKind::Gold);
+        sim.ecs.write_storage::<Inventory>().get_mut(worker).unwrap().add(ResourceKind::Gold, 3);
+        let now = sim.ecs.fetch::<SimClock>().now();
+        assert!(sim.ecs.write_storage::<Actor>().get_mut(worker).unwrap().new_action(ActionType::Deliver(stockpile), now));
+        while sim.ecs.read_storage::<Actor>().get(worker).unwrap().is_busy() {
+            sim.step();
+        }
+        assert_eq!(sim.ecs.read_storage::<Actor>().get(worker).unwrap().failure(), Some(ActionFailure::OutOfReach(at.r, at.c)));
+        assert_eq!(sim.ecs.fetch::<Resources>().get(ResourceKind::Gold), before);
+        assert_eq!(sim.ecs.read_storage::<Inventory>().get(worker).unwrap().count(ResourceKind::Gold), 3);
+    }
+
+    // a corridor one tile wide, r runs along it
+    const CORRIDOR: &str = "[meta]\nspawn = 1,1\nspawn = 5,1\n[legend]\n. = floor\nw = wall\n[map]\nwwwwwwwwww\nw........w\nwwwwwwwwww\n";
+    // two corridors joined at both ends
+    const LOOP: &str = "[meta]\nspawn = 1,1\nspawn = 5,1\n[legend]\n. = floor\nw = wall\n[map]\nwwwwwwwwwww\nw.........w\nw.wwwwwww.w\nw.........w\nwwwwwwwwwww\n";
+
+    fn worker_at(sim: &Simulation, pos: (u32, u32)) -> Entity {
+        (&sim.ecs.entities(), &sim.ecs.read_storage::<Transform>(), &sim.ecs.read_storage::<Worker>()).join()
+            .find(|(_, trans, _)| (trans.r, trans.c) == pos)
+            .unwrap().0
+    }
+
+    fn position(sim: &Simulation, entity: Entity) -> (u32, u32) {
+        let trans = sim.ecs.read_storage::<Transform>().get(entity).cloned().unwrap();
+        (trans.r, trans.c)
+    }
+
+    fn order(sim: &mut Simulation, entity: Entity, task: WorkerTask) {
+        sim.ecs.write_storage::<Worker>().get_mut(entity).unwrap().order(task);
+    }
+
+    // sleeps on the spot for far longer than any of these tests run
+    fn put_to_sleep(sim: &mut Simulation, entity: Entity) {
+        sim.ecs.write_storage::<Needs>().get_mut(entity).unwrap().fatigue = crate::needs::NEED_MAX;
+        order(sim, entity, WorkerTask::Sleep);
+    }
+
+    #[test]
+    fn idle_worker_trades_places_in_a_corridor() {
+        let mut sim = Simulation::from_text(0, CORRIDOR);
+        let (walker, idler) = (worker_at(&sim, (1, 1)), worker_at(&sim, (5, 1)));
+        order(&mut sim, walker, WorkerTask::MoveTo(8, 1));
+        sim.step_n(300);
+        assert_eq!(position(&sim, walker), (8, 1));
+        assert!(position(&sim, idler).0 < 8);
+    }
+
+    #[test]
+    fn blocked_walker_goes_the_other_way_round() {
+        let mut sim = Simulation::from_text(0, LOOP);
+        let (walker, sleeper) = (worker_at(&sim, (1, 1)), worker_at(&sim, (5, 1)));
+        put_to_sleep(&mut sim, sleeper);
+        order(&mut sim, walker, WorkerTask::MoveTo(9, 1));
+        sim.step_n(400);
+        assert_eq!(position(&sim, sleeper), (5, 1));
+        assert_eq!(position(&sim, walker), (9, 1));
+    }
+
+    #[test]
+    fn blocked_walker_gives_up() {
+        let mut sim = Simulation::from_text(0, CORRIDOR);
+        let (walker, sleeper) = (worker_at(&sim, (1, 1)), worker_at(&sim, (5, 1)));
+        put_to_sleep(&mut sim, sleeper);
+        order(&mut sim, walker, WorkerTask::MoveTo(8, 1));
+        // still waiting well past the first few tries to get round
+        sim.step_n(100 + REROUTE_CHECKS * 2);
+        assert!(sim.ecs.read_storage::<Worker>().get(walker).unwrap().task == WorkerTask::MoveTo(8, 1));
+        assert_eq!(position(&sim, walker), (4, 1));
+
+        sim.step_n(GIVE_UP_CHECKS);
+        assert!(sim.ecs.read_storage::<Worker>().get(walker).unwrap().task == WorkerTask::Idle);
+        assert!(position(&sim, walker).0 < 5);
     }
 }
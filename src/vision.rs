@@ -0,0 +1,182 @@
+use serde::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+use specs::System;
+use specs::{WriteExpect, ReadStorage, WriteStorage};
+use specs::Join;
+
+use super::Transform;
+use super::map::Map;
+use super::combat::Faction;
+
+/// What an entity can see from where it stands.
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Viewshed {
+    pub range: i32,
+    pub visible: Vec<(u32, u32)>,
+    pub dirty: bool,// set this when walls change under it
+    origin: Option<(u32, u32)>,
+    map_revision: u32,// terrain this was worked out on
+}
+
+impl Viewshed {
+    pub fn new(range: i32) -> Self {
+        Self {
+            range,
+            visible: vec![],
+            dirty: true,
+            origin: None,
+            map_revision: 0,
+        }
+    }
+}
+
+// An exact slope num/den, den is always positive.
+#[derive(Clone, Copy)]
+struct Slope {
+    num: i32,
+    den: i32,
+}
+
+impl Slope {
+    // depth * slope rounded to the nearest column, ties go up
+    fn round_up(self, depth: i32) -> i32 {
+        (2 * depth * self.num + self.den).div_euclid(2 * self.den)
+    }
+    // depth * slope rounded to the nearest column, ties go down
+    fn round_down(self, depth: i32) -> i32 {
+        -(self.den - 2 * depth * self.num).div_euclid(2 * self.den)
+    }
+}
+
+struct Row {
+    depth: i32,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    fn next(&self) -> Row {
+        Row { depth: self.depth + 1, start: self.start, end: self.end }
+    }
+    // whether a floor tile is seen the same way from both ends
+    fn is_symmetric(&self, col: i32) -> bool {
+        col * self.start.den >= self.depth * self.start.num && col * self.end.den <= self.depth * self.end.num
+    }
+}
+
+// slope to the near edge of a tile
+fn slope(depth: i32, col: i32) -> Slope {
+    Slope { num: 2 * col - 1, den: 2 * depth }
+}
+
+/// Symmetric shadowcasting: if a can see b then b can see a. Walls are lit but not seen
+/// through, `mark` gets called for every tile in sight within `range`, possibly more than once.
+pub fn field_of_view(origin: (i32, i32), range: i32, is_opaque: impl Fn(i32, i32) -> bool, mut mark: impl FnMut(i32, i32)) {
+    mark(origin.0, origin.1);
+    for quadrant in 0..4 {
+        let transform = |depth: i32, col: i32| match quadrant {
+            0 => (origin.0 + col, origin.1 - depth),
+            1 => (origin.0 + depth, origin.1 + col),
+            2 => (origin.0 + col, origin.1 + depth),
+            _ => (origin.0 - depth, origin.1 + col),
+        };
+        let mut rows = vec![Row { depth: 1, start: Slope { num: -1, den: 1 }, end: Slope { num: 1, den: 1 } }];
+        while let Some(mut row) = rows.pop() {
+            if row.depth > range {
+                continue;
+            }
+            let mut prev_wall = None;
+            for col in row.start.round_up(row.depth)..=row.end.round_down(row.depth) {
+                let (x, y) = transform(row.depth, col);
+                let wall = is_opaque(x, y);
+                if (wall || row.is_symmetric(col)) && col * col + row.depth * row.depth <= range * range {
+                    mark(x, y);
+                }
+                if prev_wall == Some(true) && !wall {
+                    row.start = slope(row.depth, col);
+                }
+                if prev_wall == Some(false) && wall {
+                    let mut next = row.next();
+                    next.end = slope(row.depth, col);
+                    rows.push(next);
+                }
+                prev_wall = Some(wall);
+            }
+            if prev_wall == Some(false) {
+                rows.push(row.next());
+            }
+        }
+    }
+}
+
+/// Recomputes viewsheds that moved and rebuilds the map's visible layer from the player's.
+pub struct VisibilitySystem;
+
+impl<'a> System<'a> for VisibilitySystem {
+    type SystemData = ( WriteExpect<'a, Map>,
+                        ReadStorage<'a, Transform>,
+                        ReadStorage<'a, Faction>,
+                        WriteStorage<'a, Viewshed>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (mut map, transforms, factions, mut viewsheds) = data;
+        map.clear_visible();
+        for (viewshed, transform, faction) in (&mut viewsheds, &transforms, factions.maybe()).join() {
+            let origin = (transform.r, transform.c);
+            // digging opens up new lines of sight
+            if viewshed.dirty || viewshed.origin != Some(origin) || viewshed.map_revision != map.revision() {
+                let mut visible = vec![];
+                field_of_view((origin.0 as i32, origin.1 as i32), viewshed.range,
+                    |r, c| map.is_opaque(r, c),
+                    |r, c| if map.is_on(r, c) { visible.push((r as u32, c as u32)) });
+                visible.sort_unstable();
+                visible.dedup();
+                viewshed.visible = visible;
+                viewshed.origin = Some(origin);
+                viewshed.map_revision = map.revision();
+                viewshed.dirty = false;
+            }
+            // monsters look around too, but only the player's side shows what it sees
+            if faction != Some(&Faction::Player) {
+                continue;
+            }
+            for &(r, c) in viewshed.visible.iter() {
+                map.see(r, c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn seen(origin: (i32, i32), range: i32, walls: &[(i32, i32)]) -> HashSet<(i32, i32)> {
+        let mut seen = HashSet::new();
+        field_of_view(origin, range, |r, c| walls.contains(&(r, c)), |r, c| { seen.insert((r, c)); });
+        seen
+    }
+
+    #[test]
+    fn walls_cast_shadows() {
+        // a short wall two tiles out, straight along r
+        let walls = [(7, 4), (7, 5), (7, 6)];
+        let seen = seen((5, 5), 8, &walls);
+        assert!(seen.contains(&(7, 5)), "the wall itself is lit");
+        assert!(!seen.contains(&(8, 5)) && !seen.contains(&(11, 5)), "seen through the wall");
+        assert!(seen.contains(&(5, 11)) && seen.contains(&(1, 5)), "open ground is hidden");
+        assert!(!seen.contains(&(5, 14)), "seen past the range");
+    }
+
+    #[test]
+    fn sight_goes_both_ways() {
+        let walls = [(3, 2), (4, 4), (6, 3), (2, 6)];
+        let from = seen((4, 3), 6, &walls);
+        for &(r, c) in from.iter().filter(|at| !walls.contains(at)) {
+            assert!(seen((r, c), 6, &walls).contains(&(4, 3)), "{},{} is seen but can't see back", r, c);
+        }
+    }
+}